@@ -1,6 +1,7 @@
 ﻿use bevy::prelude::*;
 use bevy_aseprite::Aseprite;
 use bevy_asset_loader::prelude::AssetCollection;
+use bevy_ecs_ldtk::LdtkAsset;
 
 
 #[derive(AssetCollection, Resource)]
@@ -27,4 +28,10 @@ pub struct MenuSprites {
     pub button_hover: Handle<Image>,
     //#[asset(path = "spells_art/Explosion_indicator.aseprite")]
     //pub explosion_spell: Handle<Aseprite>,
-}
\ No newline at end of file
+}
+
+#[derive(AssetCollection, Resource)]
+pub struct MapAssets {
+    #[asset(path = "ldtk/Map.ldtk")]
+    pub map: Handle<LdtkAsset>,
+}
@@ -1,10 +1,18 @@
-﻿use bevy::prelude::{App, Bundle, Camera, Color, Commands, Component, Entity, EventReader, GlobalTransform, Plugin, Query, Res, ResMut, Resource, Vec2, Windows, With};
+﻿use bevy::prelude::{App, Bundle, Camera, Color, Commands, Component, Entity, EventReader, GlobalTransform, Input, KeyCode, Plugin, Query, Res, ResMut, Resource, Time, Transform, Vec2, Windows, With, Without};
 use bevy::render::camera::RenderTarget;
 use bevy::window::WindowResized;
 use bevy_tiled_camera::{TiledCameraBundle, WorldSpace};
-use iyes_loopless::prelude::{AppLooplessStateExt, IntoConditionalSystem};
+use iyes_loopless::prelude::{AppLooplessStateExt, ConditionHelpers, IntoConditionalSystem};
+use crate::game_state::MatchSettings;
+use crate::networking::Spectating;
+use crate::player::PlayerId;
 use crate::GameState;
 
+/// How far the free spectator camera moves in a second, in pixels
+const SPECTATOR_CAMERA_SPEED: f32 = 240.0;
+/// Switches the spectator camera to the next player, and back to the free camera after the last one
+const SPECTATOR_CAMERA_KEY: KeyCode = KeyCode::Tab;
+
 /// A plugin containing the systems and resources for the Bevy_GGF camera system to function
 pub struct CamPlugin;
 
@@ -14,7 +22,11 @@ impl Plugin for CamPlugin {
             .init_resource::<CursorWorldPos>()
             //.add_system(camera_resizer)
             .add_system( update_cursor_world_pos.run_in_state(GameState::BetweenRound))
-            .add_system( update_cursor_world_pos.run_in_state(GameState::InRound));
+            .add_system( update_cursor_world_pos.run_in_state(GameState::InRound))
+            .init_resource::<SpectatorCamera>()
+            .add_system(spectator_camera.run_in_state(GameState::BetweenRound).run_if_resource_exists::<Spectating>())
+            .add_system(spectator_camera.run_in_state(GameState::InRound).run_if_resource_exists::<Spectating>())
+            .add_enter_system(GameState::Menu, reset_spectator_camera);
 
     }
 }
@@ -27,6 +39,23 @@ pub struct CursorWorldPos {
 #[derive(PartialEq, Clone, Copy, Debug, Default, Component)]
 pub struct CameraMarker;
 
+/// What the camera of a spectator looks at
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum SpectatorCameraMode {
+    /// Moved around with the arrow keys or WASD
+    #[default]
+    Free,
+    /// Centered on the player with this handle
+    Follow(usize),
+}
+
+#[derive(PartialEq, Clone, Copy, Debug, Default, Resource)]
+pub struct SpectatorCamera {
+    pub mode: SpectatorCameraMode,
+    /// Where the camera was before the spectator moved it, it goes back there after the match
+    home: Option<Vec2>,
+}
+
 #[derive(Bundle)]
 pub struct CameraBundle{
     tiled_camera: TiledCameraBundle,
@@ -82,3 +111,70 @@ fn update_cursor_world_pos(
         cursor_world_pos.cursor_world_pos = ray.origin.truncate();
     }
 }
+
+/// Moves the camera of a spectator, they have no player of their own to look at
+fn spectator_camera(
+    keys: Res<Input<KeyCode>>,
+    time: Res<Time>,
+    match_settings: Res<MatchSettings>,
+    mut spectator_camera: ResMut<SpectatorCamera>,
+    players: Query<(&PlayerId, &Transform), Without<CameraMarker>>,
+    mut camera: Query<&mut Transform, With<CameraMarker>>,
+) {
+    let mut camera_transform = match camera.get_single_mut() {
+        Ok(transform) => transform,
+        Err(_) => return,
+    };
+    if spectator_camera.home.is_none() {
+        spectator_camera.home = Some(camera_transform.translation.truncate());
+    }
+
+    if keys.just_pressed(SPECTATOR_CAMERA_KEY) {
+        spectator_camera.mode = match spectator_camera.mode {
+            SpectatorCameraMode::Free => SpectatorCameraMode::Follow(0),
+            SpectatorCameraMode::Follow(handle) if handle + 1 < match_settings.format.player_count() => {
+                SpectatorCameraMode::Follow(handle + 1)
+            }
+            SpectatorCameraMode::Follow(_) => SpectatorCameraMode::Free,
+        };
+    }
+
+    match spectator_camera.mode {
+        SpectatorCameraMode::Free => {
+            let mut direction = Vec2::ZERO;
+            if keys.any_pressed([KeyCode::A, KeyCode::Left]) {
+                direction.x -= 1.0;
+            }
+            if keys.any_pressed([KeyCode::D, KeyCode::Right]) {
+                direction.x += 1.0;
+            }
+            if keys.any_pressed([KeyCode::S, KeyCode::Down]) {
+                direction.y -= 1.0;
+            }
+            if keys.any_pressed([KeyCode::W, KeyCode::Up]) {
+                direction.y += 1.0;
+            }
+            let offset = direction.normalize_or_zero() * SPECTATOR_CAMERA_SPEED * time.delta_seconds();
+            camera_transform.translation += offset.extend(0.0);
+        }
+        SpectatorCameraMode::Follow(handle) => {
+            // the players are only there once the round has spawned them
+            if let Some((_, transform)) = players.iter().find(|(id, _)| id.handle == handle) {
+                camera_transform.translation.x = transform.translation.x;
+                camera_transform.translation.y = transform.translation.y;
+            }
+        }
+    }
+}
+
+/// Puts the camera back where it was before spectating
+fn reset_spectator_camera(
+    mut spectator_camera: ResMut<SpectatorCamera>,
+    mut camera: Query<&mut Transform, With<CameraMarker>>,
+) {
+    if let (Some(home), Ok(mut transform)) = (spectator_camera.home, camera.get_single_mut()) {
+        transform.translation.x = home.x;
+        transform.translation.y = home.y;
+    }
+    *spectator_camera = SpectatorCamera::default();
+}
@@ -0,0 +1,525 @@
+use crate::game_state::{FriendlyFire, MatchRoster, MatchSettings, MatchState};
+use crate::map::hazards::map_sprite;
+use crate::map::{enum_field, malformed_entity, parse_team, MapCollider};
+use crate::physics::ColliderOffset;
+use crate::player::{Health, PlayerId, TeamId};
+use crate::FRAME_TIME;
+use bevy::asset::{AssetServer, Assets, Handle};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::{
+    default, Bundle, Color, Commands, Component, Entity, FromReflect, GlobalTransform, Image,
+    Query, Reflect, Res, ResMut, Resource, Sprite, SpriteBundle, TextureAtlas, Transform,
+    TransformBundle, Vec2, Visibility, With, Without,
+};
+use bevy_ecs_ldtk::ldtk::{LayerInstance, TilesetDefinition};
+use bevy_ecs_ldtk::prelude::LdtkEntity;
+use bevy_ecs_ldtk::EntityInstance;
+use bevy_sepax2d::prelude::Sepax;
+use bevy_sepax2d::Convex;
+use sepax2d::prelude::{Circle, AABB};
+use sepax2d::sat_overlap;
+use serde::{Deserialize, Serialize};
+
+/// How close a player has to get to the orb to pick it up
+pub const ORB_PICKUP_RADIUS: f32 = 12.0;
+
+/// The game modes a match can be played in. Each mode decides how teams score points during a round
+/// and when the round is won
+#[derive(
+    FromReflect,
+    Reflect,
+    Serialize,
+    Deserialize,
+    Default,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Debug,
+    Copy,
+    Clone,
+)]
+pub enum GameMode {
+    /// Players don't respawn, the last team with players alive wins the round
+    #[default]
+    Elimination,
+    /// Players respawn, the first team to the kill limit wins the round
+    TeamDeathmatch,
+    /// Players respawn, the first team to hold the hill alone for the hill time wins the round
+    KingOfTheHill,
+    /// Players respawn, the first team to bring the orb to their goal enough times wins the round
+    CaptureTheOrb,
+}
+
+impl GameMode {
+    pub const ALL: [GameMode; 4] = [
+        GameMode::Elimination,
+        GameMode::TeamDeathmatch,
+        GameMode::KingOfTheHill,
+        GameMode::CaptureTheOrb,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameMode::Elimination => "ELIMINATION",
+            GameMode::TeamDeathmatch => "TEAM DEATHMATCH",
+            GameMode::KingOfTheHill => "KING OF THE HILL",
+            GameMode::CaptureTheOrb => "CAPTURE THE ORB",
+        }
+    }
+
+    /// The rules of the mode. This is the only place the simulation picks what to do by mode
+    pub fn rules(&self) -> &'static dyn ModeRules {
+        match self {
+            GameMode::Elimination => &EliminationRules,
+            GameMode::TeamDeathmatch => &TeamDeathmatchRules,
+            GameMode::KingOfTheHill => &KingOfTheHillRules,
+            GameMode::CaptureTheOrb => &CaptureTheOrbRules,
+        }
+    }
+}
+
+/// What sets the game modes apart: how a round is set up, how teams score and when the round is won.
+/// The defaults are those of a mode where players respawn and the round is won on points
+pub trait ModeRules {
+    /// Whether dead players come back during the round
+    fn respawns(&self) -> bool {
+        true
+    }
+
+    /// Whether killing an enemy scores a point for the team
+    fn scores_kills(&self) -> bool {
+        false
+    }
+
+    /// The points a team needs to win the round, none if the round isn't won on points
+    fn point_limit(&self, settings: &MatchSettings) -> Option<f32>;
+
+    /// Puts the state of the mode back to how a round starts
+    fn setup_round(&self, _orb_state: &mut OrbState) {}
+
+    /// Runs every frame of the fight, scores the points the teams made during the frame
+    fn score(&self, _params: &mut ModeParams) {}
+
+    /// Returns the result of the round if it is over. The outer option is none while the round is
+    /// still going, the inner option is none when the round ended in a draw
+    fn round_result(
+        &self,
+        settings: &MatchSettings,
+        _players: &[(TeamId, bool)],
+        round_points: &[f32],
+    ) -> Option<Option<usize>> {
+        let limit = self.point_limit(settings)?;
+        round_points
+            .iter()
+            .position(|points| *points >= limit)
+            .map(Some)
+    }
+}
+
+struct EliminationRules;
+
+impl ModeRules for EliminationRules {
+    fn respawns(&self) -> bool {
+        false
+    }
+
+    fn point_limit(&self, _settings: &MatchSettings) -> Option<f32> {
+        None
+    }
+
+    /// The last team with players alive wins the round
+    fn round_result(
+        &self,
+        _settings: &MatchSettings,
+        players: &[(TeamId, bool)],
+        _round_points: &[f32],
+    ) -> Option<Option<usize>> {
+        last_team_standing(players)
+    }
+}
+
+struct TeamDeathmatchRules;
+
+impl ModeRules for TeamDeathmatchRules {
+    fn scores_kills(&self) -> bool {
+        true
+    }
+
+    fn point_limit(&self, settings: &MatchSettings) -> Option<f32> {
+        Some(settings.kill_limit as f32)
+    }
+}
+
+struct KingOfTheHillRules;
+
+impl ModeRules for KingOfTheHillRules {
+    fn point_limit(&self, settings: &MatchSettings) -> Option<f32> {
+        Some(settings.hill_time)
+    }
+
+    /// Gives the hill time to the team holding the hill, as long as no other team is contesting it
+    fn score(&self, params: &mut ModeParams) {
+        // collect and sort for determinism
+        let mut hills = params.hills.iter().collect::<Vec<_>>();
+        hills.sort_by_key(|x| x.0);
+
+        let mut teams_on_hill = params
+            .players
+            .iter()
+            .filter(|(_, _, player_sepax, _, health)| {
+                health.current_health > 0
+                    && hills.iter().any(|(_, hill_sepax)| {
+                        sat_overlap(hill_sepax.shape(), player_sepax.shape())
+                    })
+            })
+            .map(|(_, team_id, ..)| *team_id)
+            .collect::<Vec<_>>();
+        teams_on_hill.sort();
+        teams_on_hill.dedup();
+
+        if let [team_id] = teams_on_hill.as_slice() {
+            params.match_state.add_points(*team_id, FRAME_TIME);
+        }
+    }
+}
+
+struct CaptureTheOrbRules;
+
+impl ModeRules for CaptureTheOrbRules {
+    fn point_limit(&self, settings: &MatchSettings) -> Option<f32> {
+        Some(settings.capture_limit as f32)
+    }
+
+    fn setup_round(&self, orb_state: &mut OrbState) {
+        *orb_state = OrbState::default();
+    }
+
+    /// Picks up, carries, drops and scores the orb
+    fn score(&self, params: &mut ModeParams) {
+        let spawn = match orb_spawn_position(&params.orb_spawns) {
+            Some(spawn) => spawn,
+            None => return,
+        };
+
+        // collect and sort for determinism
+        let mut goals = params.goals.iter().collect::<Vec<_>>();
+        goals.sort_by_key(|x| x.0);
+
+        let mut players = params.players.iter().collect::<Vec<_>>();
+        players.sort_by_key(|x| x.0);
+
+        if let Some(carrier) = params.orb_state.carrier {
+            match players.iter().find(|(id, ..)| id.handle == carrier) {
+                Some((_, team_id, sepax, transform, health)) => {
+                    if health.current_health == 0 {
+                        *params.orb_state = OrbState {
+                            carrier: None,
+                            dropped_at: Some(transform.translation.truncate()),
+                        };
+                    } else if goals.iter().any(|(_, goal_sepax, goal)| {
+                        // goals belong to a side of the map, in free for all that is where the
+                        // carrier spawned
+                        params
+                            .roster
+                            .player(PlayerId { handle: carrier })
+                            .map(|player| player.spawn_side)
+                            == Some(goal.team_id)
+                            && sat_overlap(goal_sepax.shape(), sepax.shape())
+                    }) {
+                        params.match_state.add_points(**team_id, 1.0);
+                        *params.orb_state = OrbState::default();
+                    }
+                }
+                None => *params.orb_state = OrbState::default(),
+            }
+            return;
+        }
+
+        let position = params.orb_state.position(spawn, None);
+        let orb = Circle {
+            position: (position.x, position.y),
+            radius: ORB_PICKUP_RADIUS,
+        };
+        if let Some((id, ..)) = players.iter().find(|(_, _, sepax, _, health)| {
+            health.current_health > 0 && sat_overlap(&orb, sepax.shape())
+        }) {
+            *params.orb_state = OrbState {
+                carrier: Some(id.handle),
+                dropped_at: None,
+            };
+        }
+    }
+}
+
+fn last_team_standing(players: &[(TeamId, bool)]) -> Option<Option<usize>> {
+    let mut teams = players.iter().map(|(team, _)| team.id).collect::<Vec<_>>();
+    teams.sort();
+    teams.dedup();
+
+    let mut alive_teams = players
+        .iter()
+        .filter(|(_, alive)| *alive)
+        .map(|(team, _)| team.id)
+        .collect::<Vec<_>>();
+    alive_teams.sort();
+    alive_teams.dedup();
+
+    // with a single team the round would be over right away, so it just keeps going
+    if teams.len() < 2 || alive_teams.len() > 1 {
+        None
+    } else {
+        Some(alive_teams.first().copied())
+    }
+}
+
+/// The match rules the local player votes for in the lobby
+#[derive(Resource, Default, Debug, Copy, Clone, PartialEq)]
+pub struct ModeVote {
+    pub mode: GameMode,
+    pub friendly_fire: FriendlyFire,
+}
+
+/// Picks the option with the most votes, ties go to the option listed first
+pub fn resolve_vote<T: Copy + PartialEq>(options: &[T], votes: &[T]) -> T {
+    let (_, option) = options
+        .iter()
+        .enumerate()
+        .max_by_key(|(index, option)| {
+            let count = votes.iter().filter(|vote| vote == option).count();
+            // max_by_key returns the last of equal elements, so earlier options need to rank higher
+            (count, std::cmp::Reverse(*index))
+        })
+        .expect("There has to be at least one option to vote on");
+    *option
+}
+
+/// The area teams fight over in king of the hill
+#[derive(Default, Component, Debug, Copy, Clone, PartialEq)]
+pub struct HillZone;
+
+#[derive(Bundle)]
+pub struct HillZoneBundle {
+    sepax: Sepax,
+    collider_offset: ColliderOffset,
+    sprite_bundle: SpriteBundle,
+    hill_zone: HillZone,
+    map_collider: MapCollider,
+}
+
+impl LdtkEntity for HillZoneBundle {
+    fn bundle_entity(
+        entity_instance: &EntityInstance,
+        layer_instance: &LayerInstance,
+        tileset: Option<&Handle<Image>>,
+        tileset_definition: Option<&TilesetDefinition>,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) -> Self {
+        let (sepax, collider_offset) = area_collider(entity_instance);
+        HillZoneBundle {
+            sepax,
+            collider_offset,
+            sprite_bundle: map_sprite(entity_instance, Color::rgba(0.96, 0.71, 0.11, 0.35)),
+            hill_zone: HillZone,
+            map_collider: MapCollider,
+        }
+    }
+}
+
+/// Where the orb sits at the start of a capture the orb round
+#[derive(Default, Component, Debug, Copy, Clone, PartialEq)]
+pub struct OrbSpawn;
+
+#[derive(Bundle)]
+pub struct OrbSpawnBundle {
+    transform_bundle: TransformBundle,
+    orb_spawn: OrbSpawn,
+}
+
+impl LdtkEntity for OrbSpawnBundle {
+    fn bundle_entity(
+        entity_instance: &EntityInstance,
+        layer_instance: &LayerInstance,
+        tileset: Option<&Handle<Image>>,
+        tileset_definition: Option<&TilesetDefinition>,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) -> Self {
+        OrbSpawnBundle {
+            transform_bundle: Default::default(),
+            orb_spawn: OrbSpawn,
+        }
+    }
+}
+
+/// The area a team has to carry the orb into to score. In free for all every player scores in the goal
+/// on the side of the map they spawn on
+#[derive(Default, Component, Debug, Copy, Clone, PartialEq)]
+pub struct OrbGoal {
+    pub team_id: TeamId,
+}
+
+#[derive(Bundle)]
+pub struct OrbGoalBundle {
+    sepax: Sepax,
+    collider_offset: ColliderOffset,
+    sprite_bundle: SpriteBundle,
+    orb_goal: OrbGoal,
+    map_collider: MapCollider,
+}
+
+impl LdtkEntity for OrbGoalBundle {
+    fn bundle_entity(
+        entity_instance: &EntityInstance,
+        layer_instance: &LayerInstance,
+        tileset: Option<&Handle<Image>>,
+        tileset_definition: Option<&TilesetDefinition>,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) -> Self {
+        let team_id = enum_field(entity_instance, "Team")
+            .and_then(parse_team)
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+
+        let (sepax, collider_offset) = area_collider(entity_instance);
+        OrbGoalBundle {
+            sepax,
+            collider_offset,
+            sprite_bundle: map_sprite(entity_instance, Color::rgba(0.71, 0.13, 0.16, 0.35)),
+            orb_goal: OrbGoal { team_id },
+            map_collider: MapCollider,
+        }
+    }
+}
+
+/// The collider of an area covering its whole entity, centered on it like its sprite
+fn area_collider(entity_instance: &EntityInstance) -> (Sepax, ColliderOffset) {
+    let (width, height) = (entity_instance.width as f32, entity_instance.height as f32);
+    let sepax = Sepax {
+        convex: Convex::AABB(AABB::new((0.0, 0.0), width, height)),
+    };
+    (sepax, ColliderOffset::centered(width, height))
+}
+
+/// Where the orb is. The orb isn't an entity so it doesn't need a rollback id, its position is worked
+/// out from this rollback resource every frame instead
+#[derive(Reflect, Default, Resource, Debug, Copy, Clone, PartialEq)]
+pub struct OrbState {
+    /// The handle of the player carrying the orb
+    pub carrier: Option<usize>,
+    /// Where the orb was dropped, none while it's carried or sitting on its spawn
+    pub dropped_at: Option<Vec2>,
+}
+
+impl OrbState {
+    /// Returns where the orb currently is
+    pub fn position(&self, spawn: Vec2, carrier_position: Option<Vec2>) -> Vec2 {
+        carrier_position.or(self.dropped_at).unwrap_or(spawn)
+    }
+}
+
+/// What the game modes score with, see [`ModeRules::score`]
+#[derive(SystemParam)]
+pub struct ModeParams<'w, 's> {
+    pub match_state: ResMut<'w, MatchState>,
+    pub orb_state: ResMut<'w, OrbState>,
+    pub roster: Res<'w, MatchRoster>,
+    pub hills: Query<'w, 's, (Entity, &'static Sepax), (With<HillZone>, Without<PlayerId>)>,
+    pub orb_spawns: Query<'w, 's, &'static GlobalTransform, With<OrbSpawn>>,
+    pub goals: Query<'w, 's, (Entity, &'static Sepax, &'static OrbGoal), Without<PlayerId>>,
+    pub players: Query<
+        'w,
+        's,
+        (
+            &'static PlayerId,
+            &'static TeamId,
+            &'static Sepax,
+            &'static Transform,
+            &'static Health,
+        ),
+    >,
+}
+
+/// Scores the points of the frame by the rules of the mode the match is played in
+pub fn mode_scoring_system(match_settings: Res<MatchSettings>, mut params: ModeParams) {
+    if !params.match_state.is_fighting() {
+        return;
+    }
+    match_settings.mode.rules().score(&mut params);
+}
+
+fn orb_spawn_position(orb_spawns: &Query<&GlobalTransform, With<OrbSpawn>>) -> Option<Vec2> {
+    // maps only have one orb spawn, but pick the same one on every peer if there are more
+    orb_spawns
+        .iter()
+        .map(|transform| transform.translation().truncate())
+        .min_by(|a, b| (a.x, a.y).partial_cmp(&(b.x, b.y)).unwrap())
+}
+
+/// The sprite showing where the orb is
+#[derive(Component)]
+pub struct OrbMarker;
+
+/// Keeps the orb sprite on the orb, spawning it the first time it's needed
+pub fn update_orb_visual(
+    mut commands: Commands,
+    match_settings: Res<MatchSettings>,
+    orb_state: Res<OrbState>,
+    orb_spawns: Query<&GlobalTransform, With<OrbSpawn>>,
+    players: Query<(&PlayerId, &Transform), Without<OrbMarker>>,
+    mut orbs: Query<(&mut Transform, &mut Visibility), With<OrbMarker>>,
+) {
+    let spawn = match orb_spawn_position(&orb_spawns) {
+        Some(spawn) => spawn,
+        None => return,
+    };
+
+    let carrier_position = orb_state.carrier.and_then(|carrier| {
+        players
+            .iter()
+            .find(|(id, _)| id.handle == carrier)
+            .map(|(_, transform)| transform.translation.truncate())
+    });
+    let position = orb_state.position(spawn, carrier_position);
+    let visible = match_settings.mode == GameMode::CaptureTheOrb;
+
+    if orbs.is_empty() {
+        commands.spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    color: Color::rgb(0.9, 0.9, 1.0),
+                    custom_size: Some(Vec2::splat(ORB_PICKUP_RADIUS)),
+                    ..default()
+                },
+                transform: Transform::from_translation(position.extend(30.0)),
+                visibility: Visibility {
+                    is_visible: visible,
+                },
+                ..default()
+            },
+            OrbMarker,
+        ));
+        return;
+    }
+
+    for (mut transform, mut visibility) in orbs.iter_mut() {
+        transform.translation = position.extend(30.0);
+        visibility.is_visible = visible;
+    }
+}
+
+/// Only shows the hill and orb goals in the modes that use them
+pub fn update_mode_area_visibility(
+    match_settings: Res<MatchSettings>,
+    mut hills: Query<&mut Visibility, (With<HillZone>, Without<OrbGoal>)>,
+    mut goals: Query<&mut Visibility, (With<OrbGoal>, Without<HillZone>)>,
+) {
+    for mut visibility in hills.iter_mut() {
+        visibility.is_visible = match_settings.mode == GameMode::KingOfTheHill;
+    }
+    for mut visibility in goals.iter_mut() {
+        visibility.is_visible = match_settings.mode == GameMode::CaptureTheOrb;
+    }
+}
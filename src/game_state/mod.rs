@@ -0,0 +1,754 @@
+use crate::game_state::game_mode::{GameMode, OrbState};
+use crate::game_state::safe_zone::{SafeZone, SafeZoneSettings};
+use crate::map::hazards::Crate;
+use crate::map::pickups::PickupSpawner;
+use crate::map::{spawn_position, MapRotation, MapWorld, SpawnPoint};
+use crate::networking::desync::{DesyncChannel, FrameChecksums};
+use crate::networking::events::{ConnectionStatus, PeerHandles};
+use crate::networking::ggrs::{FrameInputs, GGRSConfig};
+use crate::networking::lobby::{LobbyState, LocalLobbyChoices};
+use crate::networking::stats::{NetworkStatsOverlay, RollbackFrame, SimulatedFrames};
+use crate::networking::{Spectating, SpectatorCount, WrtcSocket};
+use crate::physics::Movement;
+use crate::player::input::{LEVEL_LOADED, REMATCH};
+use crate::player::{
+    Health, Loadout, MageColor, MovementState, PlayerBuffs, PlayerId, PlayerMovementState,
+    PlayerSpellBuffer, RespawnTimer, TeamId,
+};
+use crate::spell::SpellLifetime;
+use crate::GameState;
+use crate::FRAME_TIME;
+use bevy::prelude::{
+    Commands, Component, DespawnRecursiveExt, Entity, FromReflect, GlobalTransform, Mut, Query,
+    Reflect, Res, ResMut, Resource, Transform, Vec3, Visibility, With, Without,
+};
+use bevy_ecs_ldtk::LevelSelection;
+use bevy_ggrs::ggrs::InputStatus;
+use bevy_ggrs::{RollbackIdProvider, Session};
+use iyes_loopless::prelude::{CurrentState, NextState};
+use serde::{Deserialize, Serialize};
+
+pub mod game_mode;
+pub mod safe_zone;
+
+/// How many players are in a match and how they are split into teams
+#[derive(Serialize, Deserialize, Eq, PartialEq, Hash, Debug, Copy, Clone)]
+pub enum MatchFormat {
+    /// Two teams of the given size
+    Teams { team_size: usize },
+    /// Every player is on their own team
+    FreeForAll { players: usize },
+}
+
+impl Default for MatchFormat {
+    fn default() -> Self {
+        MatchFormat::Teams { team_size: 1 }
+    }
+}
+
+impl MatchFormat {
+    /// The maps have spawn points for up to this many players
+    pub const MAX_PLAYERS: usize = 8;
+
+    pub const ALL: [MatchFormat; 10] = [
+        MatchFormat::Teams { team_size: 1 },
+        MatchFormat::Teams { team_size: 2 },
+        MatchFormat::Teams { team_size: 3 },
+        MatchFormat::Teams { team_size: 4 },
+        MatchFormat::FreeForAll { players: 3 },
+        MatchFormat::FreeForAll { players: 4 },
+        MatchFormat::FreeForAll { players: 5 },
+        MatchFormat::FreeForAll { players: 6 },
+        MatchFormat::FreeForAll { players: 7 },
+        MatchFormat::FreeForAll { players: 8 },
+    ];
+
+    pub fn player_count(&self) -> usize {
+        match self {
+            MatchFormat::Teams { team_size } => team_size * 2,
+            MatchFormat::FreeForAll { players } => *players,
+        }
+    }
+
+    pub fn team_count(&self) -> usize {
+        match self {
+            MatchFormat::Teams { .. } => 2,
+            MatchFormat::FreeForAll { players } => *players,
+        }
+    }
+
+    pub fn team_name(&self, team: usize) -> String {
+        match self {
+            MatchFormat::Teams { .. } => format!("TEAM {}", team + 1),
+            MatchFormat::FreeForAll { .. } => format!("PLAYER {}", team + 1),
+        }
+    }
+
+    /// A short name used in the menu and to keep the matchmaking rooms of each format apart
+    pub fn key(&self) -> String {
+        match self {
+            MatchFormat::Teams { team_size } => format!("{}v{}", team_size, team_size),
+            MatchFormat::FreeForAll { players } => format!("ffa{}", players),
+        }
+    }
+
+    /// The format for the given number of players, two teams if the players can be split evenly
+    pub fn for_player_count(players: usize) -> Option<MatchFormat> {
+        MatchFormat::ALL
+            .into_iter()
+            .find(|format| format.player_count() == players)
+    }
+
+    /// The format with the given [`MatchFormat::key`], ignoring case
+    pub fn from_key(key: &str) -> Option<MatchFormat> {
+        MatchFormat::ALL
+            .into_iter()
+            .find(|format| format.key().eq_ignore_ascii_case(key))
+    }
+}
+
+/// How much damage spells deal to the caster's teammates
+#[derive(
+    FromReflect, Reflect, Serialize, Deserialize, Default, Eq, PartialEq, Hash, Debug, Copy, Clone,
+)]
+pub enum FriendlyFire {
+    /// Teammates can't hurt each other
+    #[default]
+    Off,
+    /// Teammates take a part of the damage, see [`FRIENDLY_FIRE_REDUCED_MULTIPLIER`]
+    Reduced,
+    Full,
+}
+
+/// How much of the damage teammates take with reduced friendly fire
+pub const FRIENDLY_FIRE_REDUCED_MULTIPLIER: f32 = 0.5;
+
+impl FriendlyFire {
+    pub const ALL: [FriendlyFire; 3] =
+        [FriendlyFire::Off, FriendlyFire::Reduced, FriendlyFire::Full];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FriendlyFire::Off => "OFF",
+            FriendlyFire::Reduced => "REDUCED",
+            FriendlyFire::Full => "FULL",
+        }
+    }
+
+    /// The damage a spell deals to a teammate of its caster
+    pub fn ally_damage(&self, damage: u32) -> u32 {
+        match self {
+            FriendlyFire::Off => 0,
+            FriendlyFire::Reduced => (damage as f32 * FRIENDLY_FIRE_REDUCED_MULTIPLIER) as u32,
+            FriendlyFire::Full => damage,
+        }
+    }
+}
+
+/// The rules of a match, these have to be the same for every peer
+#[derive(Resource, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct MatchSettings {
+    /// The match is a best of this many rounds
+    pub best_of: u32,
+    pub format: MatchFormat,
+    pub mode: GameMode,
+    pub friendly_fire: FriendlyFire,
+    /// Kills a team needs to win a team deathmatch round
+    pub kill_limit: u32,
+    /// Seconds a team has to hold the hill to win a king of the hill round
+    pub hill_time: f32,
+    /// Captures a team needs to win a capture the orb round
+    pub capture_limit: u32,
+    /// Seconds the players are held on their spawn points before each round starts
+    pub countdown_duration: f32,
+    /// Seconds the result of a round is shown before the next round starts
+    pub round_over_duration: f32,
+    /// Seconds before a dead player respawns in the modes where players respawn
+    pub respawn_delay: f32,
+}
+
+impl Default for MatchSettings {
+    fn default() -> Self {
+        MatchSettings {
+            best_of: 3,
+            format: MatchFormat::default(),
+            mode: GameMode::Elimination,
+            friendly_fire: FriendlyFire::Off,
+            kill_limit: 10,
+            hill_time: 30.0,
+            capture_limit: 3,
+            countdown_duration: 3.0,
+            round_over_duration: 3.0,
+            respawn_delay: 3.0,
+        }
+    }
+}
+
+impl MatchSettings {
+    pub fn rounds_to_win(&self) -> u32 {
+        self.best_of / 2 + 1
+    }
+}
+
+/// What a player picked in the lobby that matters for the roster
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct RosterChoice {
+    /// The team the player would like to be on, none if they don't mind
+    pub team: Option<usize>,
+    pub loadout: Loadout,
+    pub color: MageColor,
+}
+
+/// A player of the match as decided in the lobby
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RosterPlayer {
+    pub team_id: TeamId,
+    /// The side of the map the player spawns on
+    pub spawn_side: TeamId,
+    /// Which spawn point of the side the player spawns on
+    pub spawn_slot: usize,
+    pub loadout: Loadout,
+    pub color: MageColor,
+}
+
+/// The players of the match, indexed by handle. Built once the lobby is over from the choices of every
+/// player, so it is the same on every peer
+#[derive(Resource, Default, Debug, Clone, PartialEq)]
+pub struct MatchRoster {
+    pub players: Vec<RosterPlayer>,
+}
+
+impl MatchRoster {
+    /// Puts every player on a team. In handle order, players get the team they asked for while it has
+    /// room, and everyone else fills up the emptiest team
+    pub fn new(format: MatchFormat, choices: &[RosterChoice]) -> Self {
+        let teams = match format {
+            MatchFormat::Teams { team_size } => {
+                let mut counts = [0; 2];
+                let mut teams = choices
+                    .iter()
+                    .map(|choice| match choice.team {
+                        Some(team) if team < 2 && counts[team] < team_size => {
+                            counts[team] += 1;
+                            Some(team)
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                for team in teams.iter_mut().filter(|team| team.is_none()) {
+                    let emptiest = if counts[1] < counts[0] { 1 } else { 0 };
+                    counts[emptiest] += 1;
+                    *team = Some(emptiest);
+                }
+                teams.into_iter().flatten().collect::<Vec<_>>()
+            }
+            MatchFormat::FreeForAll { .. } => (0..choices.len()).collect(),
+        };
+
+        let players = choices
+            .iter()
+            .zip(teams.iter())
+            .enumerate()
+            .map(|(handle, (choice, team))| {
+                // teams spawn on their own side, free for all players are spread over both sides
+                let (spawn_side, spawn_slot) = match format {
+                    MatchFormat::Teams { .. } => (
+                        *team,
+                        teams[..handle]
+                            .iter()
+                            .filter(|other| *other == team)
+                            .count(),
+                    ),
+                    MatchFormat::FreeForAll { .. } => (handle % 2, handle / 2),
+                };
+                RosterPlayer {
+                    team_id: TeamId { id: *team },
+                    spawn_side: TeamId { id: spawn_side },
+                    spawn_slot,
+                    loadout: choice.loadout,
+                    color: choice.color,
+                }
+            })
+            .collect();
+
+        MatchRoster { players }
+    }
+
+    pub fn player(&self, player_id: PlayerId) -> Option<&RosterPlayer> {
+        self.players.get(player_id.handle)
+    }
+}
+
+#[derive(FromReflect, Reflect, PartialEq, Debug, Copy, Clone)]
+pub enum RoundPhase {
+    /// Inputs are frozen while the countdown runs
+    Countdown {
+        remaining: f32,
+    },
+    Fighting,
+    /// The round has been won, the result is shown until the next round starts
+    RoundOver {
+        remaining: f32,
+    },
+    /// A team has won enough rounds to win the match
+    MatchOver,
+}
+
+impl Default for RoundPhase {
+    fn default() -> Self {
+        RoundPhase::Countdown { remaining: 0.0 }
+    }
+}
+
+/// The state of the match. This is a rollback resource and the whole round flow is simulated in the
+/// rollback schedule, so every peer ends rounds and starts new ones on exactly the same frame
+#[derive(Reflect, Default, Resource, Debug, Clone, PartialEq)]
+pub struct MatchState {
+    pub phase: RoundPhase,
+    pub round: u32,
+    /// Rounds won by each team, indexed by team id
+    pub team_scores: Vec<u32>,
+    /// Points scored by each team in the current round, indexed by team id. What a point is depends
+    /// on the game mode
+    pub round_points: Vec<f32>,
+    /// The team that won the last round, none if it was a draw
+    pub last_round_winner: Option<usize>,
+    /// A bitmask of the player handles that asked for a rematch after the match ended
+    pub rematch_votes: u32,
+    /// A bitmask of the player handles GGRS has disconnected. Their inputs are marked disconnected
+    /// from the same frame on every peer, so this is deterministic
+    pub disconnected: u32,
+    /// Set when the match ended because the other teams left
+    pub forfeit: bool,
+    /// The last team with connected players when the match was forfeited
+    pub forfeit_winner: Option<usize>,
+}
+
+impl MatchState {
+    pub fn new(settings: &MatchSettings) -> Self {
+        MatchState {
+            phase: RoundPhase::Countdown {
+                remaining: settings.countdown_duration,
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn is_fighting(&self) -> bool {
+        self.phase == RoundPhase::Fighting
+    }
+
+    pub fn add_points(&mut self, team_id: TeamId, points: f32) {
+        if self.round_points.len() <= team_id.id {
+            self.round_points.resize(team_id.id + 1, 0.0);
+        }
+        self.round_points[team_id.id] += points;
+    }
+
+    pub fn round_points(&self, team_id: usize) -> f32 {
+        self.round_points.get(team_id).copied().unwrap_or(0.0)
+    }
+
+    pub fn team_score(&self, team_id: usize) -> u32 {
+        self.team_scores.get(team_id).copied().unwrap_or(0)
+    }
+
+    /// The team that won the match, if any team has won enough rounds or every other team left
+    pub fn match_winner(&self, settings: &MatchSettings) -> Option<usize> {
+        if self.forfeit {
+            return self.forfeit_winner;
+        }
+        self.team_scores
+            .iter()
+            .position(|score| *score >= settings.rounds_to_win())
+    }
+
+    pub fn is_disconnected(&self, handle: usize) -> bool {
+        self.disconnected & (1 << handle) != 0
+    }
+
+    fn add_round_win(&mut self, team_id: usize) {
+        if self.team_scores.len() <= team_id {
+            self.team_scores.resize(team_id + 1, 0);
+        }
+        self.team_scores[team_id] += 1;
+    }
+}
+
+/// Set by the post match screen when the local player wants a rematch, sent to the other peers
+/// through the inputs
+#[derive(Resource, Default, Debug, Copy, Clone, PartialEq)]
+pub struct LocalRematchVote {
+    pub requested: bool,
+}
+
+type RoundPlayers<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static PlayerId,
+        &'static TeamId,
+        &'static mut Health,
+        &'static mut Transform,
+        &'static mut PlayerMovementState,
+        &'static mut Movement,
+        &'static mut PlayerBuffs,
+        &'static mut RespawnTimer,
+    ),
+    Without<Crate>,
+>;
+
+/// Runs the round state machine: counts down the countdown, detects the end of the round, keeps the
+/// match score and resets everything for the next round
+pub fn round_system(
+    frame: Res<RollbackFrame>,
+    mut match_state: ResMut<MatchState>,
+    match_settings: Res<MatchSettings>,
+    mut safe_zone: ResMut<SafeZone>,
+    safe_zone_settings: Res<SafeZoneSettings>,
+    mut orb_state: ResMut<OrbState>,
+    inputs: Res<FrameInputs>,
+    mut players: RoundPlayers,
+    mut crates: Query<&mut Health, (With<Crate>, Without<PlayerId>)>,
+    mut pickups: Query<&mut PickupSpawner>,
+    mut spells: Query<&mut SpellLifetime>,
+    spawn_points: Query<(&GlobalTransform, &SpawnPoint)>,
+    roster: Res<MatchRoster>,
+) {
+    // disconnected players stay dead, and forfeit the match once no one is left on their team
+    for (id, _, mut health, ..) in players.iter_mut() {
+        if matches!(inputs[id.handle].1, InputStatus::Disconnected) {
+            match_state.disconnected |= 1 << id.handle;
+            health.current_health = 0;
+        }
+    }
+    if match_state.disconnected != 0 && match_state.phase != RoundPhase::MatchOver {
+        // collect and sort for determinism
+        let mut teams = players
+            .iter()
+            .filter(|(id, ..)| !match_state.is_disconnected(id.handle))
+            .map(|(_, team, ..)| team.id)
+            .collect::<Vec<_>>();
+        teams.sort();
+        teams.dedup();
+        if teams.len() <= 1 {
+            match_state.forfeit = true;
+            match_state.forfeit_winner = teams.first().copied();
+            match_state.phase = RoundPhase::MatchOver;
+            return;
+        }
+    }
+
+    match match_state.phase {
+        RoundPhase::Countdown { remaining } => {
+            // levels load asynchronously, so after the map rotation peers get the new level at
+            // different times. The countdown doesn't start until every peer has it, and until then
+            // nobody is moved to spawn points that may only exist on some peers
+            if !level_loaded_everywhere(&match_state, &inputs) {
+                return;
+            }
+
+            // the level may have only just been swapped by the map rotation, so the players are
+            // kept on the spawn points for the whole countdown
+            let spawn_points = sorted_spawn_points(&spawn_points);
+            for (id, mut transform) in sorted_players(&mut players) {
+                if let Some(position) = player_spawn_position(&spawn_points, &roster, id) {
+                    transform.translation.x = position.x;
+                    transform.translation.y = position.y;
+                }
+            }
+
+            let remaining = remaining - FRAME_TIME;
+            if remaining <= 0.0 {
+                *safe_zone = SafeZone::new(&safe_zone_settings);
+                match_state.phase = RoundPhase::Fighting;
+            } else {
+                match_state.phase = RoundPhase::Countdown { remaining };
+            }
+        }
+        RoundPhase::Fighting => {
+            // collect and sort for determinism
+            let mut info = players
+                .iter()
+                .map(|(id, team, health, ..)| (*id, *team, health.current_health > 0))
+                .collect::<Vec<_>>();
+            info.sort_by_key(|x| x.0);
+            let info = info
+                .into_iter()
+                .map(|(_, team, alive)| (team, alive))
+                .collect::<Vec<_>>();
+
+            if let Some(winner) = match_settings.mode.rules().round_result(
+                &match_settings,
+                &info,
+                &match_state.round_points,
+            ) {
+                if let Some(winner) = winner {
+                    match_state.add_round_win(winner);
+                }
+                match_state.last_round_winner = winner;
+                match_state.phase = RoundPhase::RoundOver {
+                    remaining: match_settings.round_over_duration,
+                };
+            }
+        }
+        RoundPhase::RoundOver { remaining } => {
+            let remaining = remaining - FRAME_TIME;
+            if remaining > 0.0 {
+                match_state.phase = RoundPhase::RoundOver { remaining };
+            } else if match_state.match_winner(&match_settings).is_some() {
+                match_state.phase = RoundPhase::MatchOver;
+            } else {
+                match_state.round += 1;
+                match_state.round_points.clear();
+                match_state.phase = RoundPhase::Countdown {
+                    remaining: match_settings.countdown_duration,
+                };
+                reset_round(
+                    frame.frame,
+                    &mut players,
+                    &mut crates,
+                    &mut pickups,
+                    &mut spells,
+                );
+                *safe_zone = SafeZone::new(&safe_zone_settings);
+                match_settings.mode.rules().setup_round(&mut orb_state);
+            }
+        }
+        RoundPhase::MatchOver => {
+            // everyone still connected has to ask for the rematch before it starts
+            let mut player_count = 0;
+            for (id, ..) in players.iter() {
+                if match_state.is_disconnected(id.handle) {
+                    continue;
+                }
+                player_count += 1;
+                let (input, _) = inputs[id.handle];
+                if input.action_vars & REMATCH != 0 {
+                    match_state.rematch_votes |= 1 << id.handle;
+                }
+            }
+
+            // a forfeited match can't be replayed without the players that left
+            if !match_state.forfeit
+                && player_count > 0
+                && match_state.rematch_votes.count_ones() == player_count
+            {
+                *match_state = MatchState {
+                    disconnected: match_state.disconnected,
+                    ..MatchState::new(&match_settings)
+                };
+                reset_round(
+                    frame.frame,
+                    &mut players,
+                    &mut crates,
+                    &mut pickups,
+                    &mut spells,
+                );
+                *safe_zone = SafeZone::new(&safe_zone_settings);
+                match_settings.mode.rules().setup_round(&mut orb_state);
+            }
+        }
+    }
+}
+
+/// Whether every connected player has the current level loaded, as told by their inputs
+fn level_loaded_everywhere(match_state: &MatchState, inputs: &FrameInputs) -> bool {
+    inputs.iter().enumerate().all(|(handle, (input, _))| {
+        match_state.is_disconnected(handle) || input.action_vars & LEVEL_LOADED != 0
+    })
+}
+
+/// Gives every player their full health back, clears the spells still flying around and repairs the
+/// map for the next round. The spells are spent rather than despawned, a rollback can bring them back
+fn reset_round(
+    frame: u32,
+    players: &mut RoundPlayers,
+    crates: &mut Query<&mut Health, (With<Crate>, Without<PlayerId>)>,
+    pickups: &mut Query<&mut PickupSpawner>,
+    spells: &mut Query<&mut SpellLifetime>,
+) {
+    for mut lifetime in spells.iter_mut() {
+        if lifetime.spent_frame == 0 {
+            lifetime.spent_frame = frame;
+        }
+    }
+
+    for (_, _, mut health, _, mut movement_state, mut movement, mut buffs, mut respawn) in
+        players.iter_mut()
+    {
+        health.current_health = health.max_health;
+        *movement_state = PlayerMovementState {
+            can_dash: true,
+            dash_cooldown: 0.0,
+            movement_state: MovementState::Idle,
+        };
+        movement.velocity = Default::default();
+        *buffs = PlayerBuffs::default();
+        *respawn = RespawnTimer::default();
+    }
+
+    for mut health in crates.iter_mut() {
+        health.current_health = health.max_health;
+    }
+
+    for mut pickup in pickups.iter_mut() {
+        pickup.available = true;
+        pickup.respawn_timer = 0.0;
+    }
+}
+
+/// Respawns dead players on their spawn point after the respawn delay. In elimination dead players
+/// stay dead until the next round instead
+pub fn respawn_system(
+    match_state: Res<MatchState>,
+    match_settings: Res<MatchSettings>,
+    mut players: Query<(&PlayerId, &mut Health, &mut Transform, &mut RespawnTimer)>,
+    spawn_points: Query<(&GlobalTransform, &SpawnPoint)>,
+    roster: Res<MatchRoster>,
+) {
+    if !match_state.is_fighting() {
+        return;
+    }
+    if !match_settings.mode.rules().respawns() {
+        return;
+    }
+
+    let spawn_points = sorted_spawn_points(&spawn_points);
+
+    // collect and sort for determinism
+    let mut info = players.iter_mut().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+
+    for (id, mut health, mut transform, mut respawn) in info {
+        if health.current_health > 0 || match_state.is_disconnected(id.handle) {
+            continue;
+        }
+
+        if !respawn.waiting {
+            respawn.waiting = true;
+            respawn.remaining = match_settings.respawn_delay;
+            continue;
+        }
+
+        respawn.remaining -= FRAME_TIME;
+        if respawn.remaining <= 0.0 {
+            if let Some(position) = player_spawn_position(&spawn_points, &roster, *id) {
+                transform.translation.x = position.x;
+                transform.translation.y = position.y;
+            }
+            health.current_health = health.max_health;
+            *respawn = RespawnTimer::default();
+        }
+    }
+}
+
+fn sorted_players<'a>(players: &'a mut RoundPlayers) -> Vec<(PlayerId, Mut<'a, Transform>)> {
+    // collect and sort for determinism
+    let mut info = players
+        .iter_mut()
+        .map(|(id, _, _, transform, ..)| (*id, transform))
+        .collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+    info
+}
+
+fn sorted_spawn_points(
+    spawn_points: &Query<(&GlobalTransform, &SpawnPoint)>,
+) -> Vec<(Vec3, SpawnPoint)> {
+    // collect and sort for determinism
+    let mut info = spawn_points
+        .iter()
+        .map(|(transform, spawn_point)| (transform.translation(), *spawn_point))
+        .collect::<Vec<_>>();
+    info.sort_by_key(|x| x.1);
+    info
+}
+
+fn player_spawn_position(
+    spawn_points: &[(Vec3, SpawnPoint)],
+    roster: &MatchRoster,
+    player_id: PlayerId,
+) -> Option<Vec3> {
+    let player = roster.player(player_id)?;
+    spawn_position(spawn_points, player.spawn_side, player.spawn_slot)
+}
+
+/// Makes the [`GameState`] follow the phase of the match simulated in the rollback schedule
+pub fn follow_match_state(
+    mut commands: Commands,
+    match_state: Res<MatchState>,
+    state: Res<CurrentState<GameState>>,
+    mut rematch_vote: ResMut<LocalRematchVote>,
+) {
+    match state.0 {
+        GameState::BetweenRound | GameState::InRound | GameState::PostMatch => {}
+        _ => return,
+    }
+
+    let target = match match_state.phase {
+        RoundPhase::Countdown { .. } => GameState::BetweenRound,
+        RoundPhase::Fighting | RoundPhase::RoundOver { .. } => GameState::InRound,
+        RoundPhase::MatchOver => GameState::PostMatch,
+    };
+
+    if state.0 != target {
+        if state.0 == GameState::PostMatch {
+            rematch_vote.requested = false;
+        }
+        commands.insert_resource(NextState(target));
+    }
+}
+
+/// Hides dead players
+pub fn update_player_visibility(mut players: Query<(&Health, &mut Visibility), With<PlayerId>>) {
+    for (health, mut visibility) in players.iter_mut() {
+        visibility.is_visible = health.current_health > 0;
+    }
+}
+
+/// Despawns every entity with the given marker, along with its children
+pub fn despawn_with<T: Component>(mut commands: Commands, query: Query<Entity, With<T>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+/// Ends the GGRS session and resets everything the match left behind, so a new match can be started
+/// from a clean slate. Dropping the session also drops the socket, which disconnects us from the peers
+pub fn teardown_match(
+    mut commands: Commands,
+    safe_zone_settings: Res<SafeZoneSettings>,
+    mut lobby_choices: ResMut<LocalLobbyChoices>,
+    mut network_stats: ResMut<NetworkStatsOverlay>,
+) {
+    commands.remove_resource::<Session<GGRSConfig>>();
+    commands.remove_resource::<WrtcSocket>();
+    commands.remove_resource::<LobbyState>();
+    commands.remove_resource::<MapRotation>();
+    commands.remove_resource::<LevelSelection>();
+
+    commands.insert_resource(RollbackIdProvider::default());
+    commands.insert_resource(PlayerSpellBuffer::default());
+    commands.insert_resource(MatchState::default());
+    commands.insert_resource(SafeZone::new(&safe_zone_settings));
+    commands.insert_resource(OrbState::default());
+    commands.insert_resource(LocalRematchVote::default());
+    commands.insert_resource(MatchRoster::default());
+    commands.insert_resource(RollbackFrame::default());
+    commands.insert_resource(SimulatedFrames::default());
+    commands.insert_resource(ConnectionStatus::default());
+    commands.remove_resource::<PeerHandles>();
+    commands.remove_resource::<DesyncChannel>();
+    commands.remove_resource::<Spectating>();
+    commands.remove_resource::<SpectatorCount>();
+    commands.insert_resource(FrameChecksums::default());
+
+    // keep the picks for the next lobby, but don't start it ready
+    lobby_choices.ready = false;
+    // the overlay stays open if it was, but the peers are gone
+    *network_stats = NetworkStatsOverlay {
+        visible: network_stats.visible,
+        ..Default::default()
+    };
+}
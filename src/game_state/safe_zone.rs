@@ -0,0 +1,130 @@
+use crate::camera::CameraMarker;
+use crate::game_state::MatchState;
+use crate::player::{Health, PlayerId};
+use crate::FRAME_TIME;
+use bevy::prelude::{
+    Camera, GlobalTransform, Query, Reflect, Res, ResMut, Resource, Transform, Vec2, Vec3, With,
+};
+use bevy_egui::egui::{Color32, LayerId, Pos2, Stroke};
+use bevy_egui::EguiContext;
+
+/// How the safe zone behaves during a round
+#[derive(Resource, Debug, Copy, Clone, PartialEq)]
+pub struct SafeZoneSettings {
+    /// Seconds into the round before the zone starts shrinking
+    pub start_delay: f32,
+    /// Seconds the zone takes to shrink from its start radius to its end radius
+    pub shrink_duration: f32,
+    pub start_radius: f32,
+    pub end_radius: f32,
+    /// The damage dealt to every player outside the zone each damage interval
+    pub damage: u32,
+    pub damage_interval: f32,
+}
+
+impl Default for SafeZoneSettings {
+    fn default() -> Self {
+        SafeZoneSettings {
+            start_delay: 30.0,
+            shrink_duration: 60.0,
+            start_radius: 380.0,
+            end_radius: 48.0,
+            damage: 5,
+            damage_interval: 0.5,
+        }
+    }
+}
+
+/// The current state of the safe zone. Levels are centered on the world origin so the zone is too.
+/// This is a rollback resource so every peer shrinks the zone and deals its damage on the same frame
+#[derive(Reflect, Default, Resource, Debug, Copy, Clone, PartialEq)]
+pub struct SafeZone {
+    pub elapsed: f32,
+    pub radius: f32,
+    pub damage_timer: f32,
+}
+
+impl SafeZone {
+    pub fn new(settings: &SafeZoneSettings) -> Self {
+        SafeZone {
+            elapsed: 0.0,
+            radius: settings.start_radius,
+            damage_timer: 0.0,
+        }
+    }
+
+    pub fn contains(&self, position: Vec2) -> bool {
+        position.length() <= self.radius
+    }
+}
+
+/// Shrinks the safe zone once the start delay is over and damages every player outside of it. The
+/// zone is reset by the [`round_system`](crate::game_state::round_system) at the start of every round
+pub fn safe_zone_system(
+    mut safe_zone: ResMut<SafeZone>,
+    settings: Res<SafeZoneSettings>,
+    match_state: Res<MatchState>,
+    mut players: Query<(&PlayerId, &Transform, &mut Health)>,
+) {
+    if !match_state.is_fighting() {
+        return;
+    }
+
+    safe_zone.elapsed += FRAME_TIME;
+
+    let shrink_time = safe_zone.elapsed - settings.start_delay;
+    if shrink_time < 0.0 {
+        return;
+    }
+
+    let progress = (shrink_time / settings.shrink_duration).clamp(0.0, 1.0);
+    safe_zone.radius =
+        settings.start_radius + (settings.end_radius - settings.start_radius) * progress;
+
+    safe_zone.damage_timer += FRAME_TIME;
+    if safe_zone.damage_timer < settings.damage_interval {
+        return;
+    }
+    safe_zone.damage_timer -= settings.damage_interval;
+
+    // collect and sort for determinism
+    let mut info = players.iter_mut().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+
+    for (_, transform, mut health) in info {
+        if !safe_zone.contains(transform.translation.truncate()) {
+            health.current_health = health.current_health.saturating_sub(settings.damage);
+        }
+    }
+}
+
+/// Draws the edge of the safe zone behind the rest of the ui
+pub fn draw_safe_zone(
+    mut egui_context: ResMut<EguiContext>,
+    safe_zone: Res<SafeZone>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<CameraMarker>>,
+) {
+    let (camera, camera_transform) = match camera_query.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+
+    let center = camera.world_to_viewport(camera_transform, Vec3::ZERO);
+    let edge = camera.world_to_viewport(camera_transform, Vec3::new(safe_zone.radius, 0.0, 0.0));
+    let viewport_size = camera.logical_viewport_size();
+
+    if let (Some(center), Some(edge), Some(viewport_size)) = (center, edge, viewport_size) {
+        // bevy's viewport origin is the bottom left, egui's is the top left
+        let center = Pos2::new(center.x, viewport_size.y - center.y);
+        let radius = (edge.x - center.x).abs();
+
+        egui_context
+            .ctx_mut()
+            .layer_painter(LayerId::background())
+            .circle_stroke(
+                center,
+                radius,
+                Stroke::new(3.0, Color32::from_rgb(228, 59, 68)),
+            );
+    }
+}
@@ -0,0 +1,357 @@
+use crate::assets::SpellSprites;
+use crate::game_state::game_mode::OrbState;
+use crate::game_state::safe_zone::{SafeZone, SafeZoneSettings};
+use crate::game_state::MatchRoster;
+use crate::map::{spawn_position, BlocksMovement, MapCollider, SpawnPoint, Wall};
+use crate::networking::desync::FrameChecksums;
+use crate::networking::ggrs::FrameInputs;
+use crate::networking::stats::{RollbackFrame, SimulatedFrames};
+use crate::player::input::LEVEL_LOADED;
+use crate::player::{
+    Health, PlayerBundle, PlayerId, PlayerMovementState, PlayerSpellBuffer, TeamId,
+};
+use crate::replay::ReplayRecorder;
+use crate::spell::SpellLifetime;
+use crate::{rollback_schedule, FRAME_TIME};
+use bevy::prelude::{
+    Entity, GlobalTransform, Handle, Schedule, Time, Transform, TransformBundle, Vec3, World,
+};
+use bevy_ggrs::ggrs::InputStatus;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use bevy_sepax2d::prelude::Sepax;
+use bevy_sepax2d::Convex;
+use sepax2d::prelude::AABB;
+
+pub use crate::game_state::game_mode::GameMode;
+pub use crate::game_state::{
+    FriendlyFire, MatchFormat, MatchSettings, MatchState, RosterChoice, RoundPhase,
+};
+pub use crate::player::input::{PlayerControls, CAST_SPELL, DASH, REMATCH};
+pub use crate::player::{Loadout, MageColor, MovementState};
+pub use crate::replay::Replay;
+pub use crate::spell::{EXPLOSION_SPELL, HEAL_SPELL};
+pub use bevy::math::Vec2;
+
+/// The inside of the arena headless matches are played in, centered on the world origin like the
+/// levels of the map
+pub const ARENA_SIZE: Vec2 = Vec2::new(640.0, 320.0);
+const WALL_THICKNESS: f32 = 16.0;
+/// The distance of the spawn points from the left and right walls
+const SPAWN_MARGIN: f32 = 64.0;
+/// The spawn points of a side are stacked vertically this far apart
+const SPAWN_SPACING: f32 = 40.0;
+/// Spawn points on each side of the arena, enough for the biggest match format
+const SPAWN_SLOTS: usize = 4;
+
+/// A match simulated without a window, rendering or audio, for automated gameplay tests. It runs the
+/// rollback schedule of the game on the inputs it is given, in a walled arena instead of a level of
+/// the map since levels can only be loaded by the asset server
+pub struct HeadlessMatch {
+    world: World,
+    schedule: Schedule,
+    /// The players whose inputs are marked disconnected, by handle
+    disconnected: Vec<bool>,
+}
+
+/// The state of a player at the end of the last simulated frame
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PlayerSnapshot {
+    pub position: Vec2,
+    pub health: u32,
+    pub max_health: u32,
+    pub team: usize,
+    pub movement_state: MovementState,
+    pub can_dash: bool,
+}
+
+impl HeadlessMatch {
+    /// A match at the start of the countdown of its first round, with the players already on their
+    /// spawn points
+    pub fn new(settings: MatchSettings, players: &[RosterChoice]) -> Self {
+        let mut world = World::new();
+        let safe_zone_settings = SafeZoneSettings::default();
+
+        world.insert_resource(settings);
+        world.insert_resource(MatchState::new(&settings));
+        world.insert_resource(MatchRoster::new(settings.format, players));
+        world.insert_resource(SafeZone::new(&safe_zone_settings));
+        world.insert_resource(safe_zone_settings);
+        world.init_resource::<OrbState>();
+        world.init_resource::<PlayerSpellBuffer>();
+        world.init_resource::<RollbackIdProvider>();
+        world.init_resource::<RollbackFrame>();
+        world.init_resource::<SimulatedFrames>();
+        world.init_resource::<FrameInputs>();
+        world.init_resource::<FrameChecksums>();
+        world.init_resource::<Time>();
+        // the headless arena stands in for the first level
+        world.insert_resource(ReplayRecorder::new(settings, 0, players.to_vec()));
+        // spells are spawned with their sprites, which are never loaded
+        world.insert_resource(SpellSprites {
+            circle_indicator: Handle::default(),
+            explosion_spell: Handle::default(),
+        });
+
+        let mut headless = HeadlessMatch {
+            world,
+            schedule: rollback_schedule(),
+            disconnected: vec![false; players.len()],
+        };
+        headless.spawn_arena();
+        headless.spawn_players();
+        headless
+    }
+
+    /// A match with the settings and players of a replay, to be played with [`Self::play_replay`]
+    pub fn from_replay(replay: &Replay) -> Self {
+        HeadlessMatch::new(replay.settings, &replay.players)
+    }
+
+    /// Adds a wall to the arena, `min` being its bottom left corner
+    pub fn add_wall(&mut self, min: Vec2, size: Vec2) -> Entity {
+        self.world
+            .spawn((
+                Sepax {
+                    convex: Convex::AABB(AABB::new((min.x, min.y), size.x, size.y)),
+                },
+                placed_at(min.extend(0.0)),
+                MapCollider,
+                Wall,
+                BlocksMovement,
+            ))
+            .id()
+    }
+
+    /// Walls all around the arena and the spawn points of both sides, facing each other
+    fn spawn_arena(&mut self) {
+        let half = ARENA_SIZE / 2.0;
+        let width = ARENA_SIZE.x + 2.0 * WALL_THICKNESS;
+
+        self.add_wall(
+            Vec2::new(-half.x - WALL_THICKNESS, -half.y - WALL_THICKNESS),
+            Vec2::new(width, WALL_THICKNESS),
+        );
+        self.add_wall(
+            Vec2::new(-half.x - WALL_THICKNESS, half.y),
+            Vec2::new(width, WALL_THICKNESS),
+        );
+        self.add_wall(
+            Vec2::new(-half.x - WALL_THICKNESS, -half.y),
+            Vec2::new(WALL_THICKNESS, ARENA_SIZE.y),
+        );
+        self.add_wall(
+            Vec2::new(half.x, -half.y),
+            Vec2::new(WALL_THICKNESS, ARENA_SIZE.y),
+        );
+
+        for side in 0..2 {
+            let x = if side == 0 {
+                -half.x + SPAWN_MARGIN
+            } else {
+                half.x - SPAWN_MARGIN
+            };
+            for index in 0..SPAWN_SLOTS {
+                let y = (index as f32 - (SPAWN_SLOTS - 1) as f32 / 2.0) * SPAWN_SPACING;
+                self.world.spawn((
+                    placed_at(Vec3::new(x, y, 0.0)),
+                    SpawnPoint {
+                        team_id: TeamId { id: side },
+                        index,
+                    },
+                ));
+            }
+        }
+    }
+
+    fn spawn_players(&mut self) {
+        // collect and sort for determinism
+        let mut spawn_points = self
+            .world
+            .query::<(&GlobalTransform, &SpawnPoint)>()
+            .iter(&self.world)
+            .map(|(transform, spawn_point)| (transform.translation(), *spawn_point))
+            .collect::<Vec<_>>();
+        spawn_points.sort_by_key(|x| x.1);
+
+        let roster = self.world.resource::<MatchRoster>().clone();
+        for (handle, roster_player) in roster.players.iter().enumerate() {
+            let spawn_point = spawn_position(
+                &spawn_points,
+                roster_player.spawn_side,
+                roster_player.spawn_slot,
+            )
+            .unwrap_or_else(|| {
+                panic!(
+                    "The headless arena only has {} spawn points per side",
+                    SPAWN_SLOTS
+                )
+            });
+            let rollback_id =
+                Rollback::new(self.world.resource_mut::<RollbackIdProvider>().next_id());
+            self.world.spawn(PlayerBundle::new(
+                handle,
+                roster_player,
+                spawn_point,
+                rollback_id,
+                Handle::default(),
+                Handle::default(),
+            ));
+        }
+    }
+
+    /// Simulates one frame with the inputs of every player, in handle order. The arena is always
+    /// loaded, so every connected player confirms it in their input
+    pub fn step(&mut self, inputs: &[PlayerControls]) {
+        assert_eq!(
+            inputs.len(),
+            self.player_count(),
+            "Every player needs an input"
+        );
+        let inputs = inputs
+            .iter()
+            .zip(self.disconnected.iter())
+            .map(|(input, disconnected)| {
+                if *disconnected {
+                    (PlayerControls::default(), InputStatus::Disconnected)
+                } else {
+                    let input = PlayerControls {
+                        action_vars: input.action_vars | LEVEL_LOADED,
+                        ..*input
+                    };
+                    (input, InputStatus::Confirmed)
+                }
+            })
+            .collect();
+        self.run_frame(inputs);
+    }
+
+    /// Simulates a number of frames, asking the script for the input of every player on every frame.
+    /// The script is given the frame being simulated and the handle of the player
+    pub fn run(&mut self, frames: u32, mut script: impl FnMut(u32, usize) -> PlayerControls) {
+        for _ in 0..frames {
+            let frame = self.frame() + 1;
+            let inputs = (0..self.player_count())
+                .map(|handle| script(frame, handle))
+                .collect::<Vec<_>>();
+            self.step(&inputs);
+        }
+    }
+
+    /// Simulates frames without any input until the condition holds for the match state, for at most
+    /// `max_frames` frames. Returns whether the condition was met
+    pub fn idle_until(&mut self, max_frames: u32, condition: impl Fn(&MatchState) -> bool) -> bool {
+        let idle = vec![PlayerControls::default(); self.player_count()];
+        for _ in 0..max_frames {
+            if condition(self.match_state()) {
+                return true;
+            }
+            self.step(&idle);
+        }
+        condition(self.match_state())
+    }
+
+    /// Idles through the countdown of the current round
+    pub fn skip_countdown(&mut self) {
+        let frames = (self.settings().countdown_duration / FRAME_TIME).ceil() as u32 + 1;
+        assert!(
+            self.idle_until(frames, MatchState::is_fighting),
+            "The countdown didn't end"
+        );
+    }
+
+    /// Plays the recorded inputs of a replay from the next frame until the end of the replay. The
+    /// inputs are played in the headless arena, not on the level the replay was recorded on
+    pub fn play_replay(&mut self, replay: &Replay) {
+        while let Some(inputs) = replay.frame_inputs(self.frame() + 1) {
+            self.run_frame(inputs);
+        }
+    }
+
+    /// The replay of the frames simulated so far. Headless inputs are confirmed as soon as they are
+    /// simulated, so every frame is in it
+    pub fn replay(&mut self) -> Replay {
+        let frame = self.frame();
+        let mut recorder = self.world.resource_mut::<ReplayRecorder>();
+        recorder.confirm(frame);
+        recorder.replay().clone()
+    }
+
+    /// From the next frame on the inputs of the player are marked disconnected, as GGRS does once a
+    /// peer is gone
+    pub fn disconnect(&mut self, handle: usize) {
+        self.disconnected[handle] = true;
+    }
+
+    fn run_frame(&mut self, inputs: Vec<(PlayerControls, InputStatus)>) {
+        self.world.insert_resource(FrameInputs(inputs));
+        self.schedule.run(&mut self.world);
+    }
+
+    /// The last simulated frame, counting from 1
+    pub fn frame(&self) -> u32 {
+        self.world.resource::<RollbackFrame>().frame
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.disconnected.len()
+    }
+
+    pub fn settings(&self) -> &MatchSettings {
+        self.world.resource::<MatchSettings>()
+    }
+
+    pub fn match_state(&self) -> &MatchState {
+        self.world.resource::<MatchState>()
+    }
+
+    pub fn player(&mut self, handle: usize) -> PlayerSnapshot {
+        self.world
+            .query::<(
+                &PlayerId,
+                &Transform,
+                &Health,
+                &TeamId,
+                &PlayerMovementState,
+            )>()
+            .iter(&self.world)
+            .find(|(id, ..)| id.handle == handle)
+            .map(|(_, transform, health, team, state)| PlayerSnapshot {
+                position: transform.translation.truncate(),
+                health: health.current_health,
+                max_health: health.max_health,
+                team: team.id,
+                movement_state: state.movement_state,
+                can_dash: state.can_dash,
+            })
+            .unwrap_or_else(|| panic!("There is no player {}", handle))
+    }
+
+    /// The spells that have been cast and haven't gone off yet or are going off. Nothing despawns
+    /// spent spells in a headless match, so they aren't counted
+    pub fn spell_count(&mut self) -> usize {
+        self.world
+            .query::<&SpellLifetime>()
+            .iter(&self.world)
+            .filter(|lifetime| lifetime.spent_frame == 0)
+            .count()
+    }
+
+    /// The checksum of the latest frame written down for desync detection
+    pub fn latest_checksum(&self) -> Option<u64> {
+        self.world
+            .resource::<FrameChecksums>()
+            .frames
+            .values()
+            .next_back()
+            .map(|state| state.checksum)
+    }
+}
+
+/// Nothing propagates transforms in the headless world, so the global transform is set up front
+fn placed_at(translation: Vec3) -> TransformBundle {
+    let transform = Transform::from_translation(translation);
+    TransformBundle {
+        local: transform,
+        global: GlobalTransform::from(transform),
+    }
+}
@@ -0,0 +1,377 @@
+use crate::assets::{MapAssets, MenuSprites, SpellSprites, Sprites};
+use crate::camera::{CamPlugin, CameraBundle};
+use crate::combat::handle_spell_buffer;
+use crate::game_state::game_mode::{
+    mode_scoring_system, update_mode_area_visibility, update_orb_visual, HillZoneBundle,
+    ModeVote, OrbGoalBundle, OrbMarker, OrbSpawnBundle, OrbState,
+};
+use crate::game_state::safe_zone::{draw_safe_zone, safe_zone_system, SafeZone, SafeZoneSettings};
+use crate::game_state::{
+    despawn_with, follow_match_state, respawn_system, round_system, teardown_match,
+    update_player_visibility, LocalRematchVote, MatchRoster, MatchSettings, MatchState,
+};
+use crate::map::hazards::{
+    crate_damage_system, lava_damage_system, push_pad_system, update_crate_visibility, CrateBundle,
+    LavaPool, LavaPoolBundle, PushPadBundle, SlowZoneBundle,
+};
+use crate::map::pickups::{
+    pickup_system, update_pickup_visibility, update_player_buffs, PickupSpawner,
+    PickupSpawnerBundle,
+};
+use crate::map::{
+    assign_map_rollback_ids, build_map_registry, build_terrain_colliders, place_levels, rotate_map,
+    spawn_position, track_level_loading, LevelLoaded, MapVote, MapWorld, SpawnPoint,
+    SpawnPointBundle,
+};
+use crate::networking::ggrs::{read_frame_inputs, GGRSConfig};
+use crate::networking::desync::record_checksum;
+use crate::networking::lobby::LocalLobbyChoices;
+use crate::networking::stats::{count_simulated_frames, RollbackFrame};
+use crate::networking::rollback_systems::{
+    handle_spell_casts, move_players, spell_collision_system, update_dash_info,
+    update_spell_lifetimes, velocity_system,
+};
+use crate::networking::{
+    start_matchbox_socket, wait_for_players, NetworkPlugin,
+};
+use crate::physics::{
+    clear_correction_system, collision_system, update_movable_system, update_walls_system, Movement,
+};
+use crate::player::input::input;
+use crate::player::{
+    update_animation_state, update_mage_colors, AnimationState, Health, LocalPlayer,
+    PlayerBuffs,
+    PlayerBundle, PlayerCombatState, PlayerId, PlayerMovementState, PlayerMovementStats,
+    PlayerSpellBuffer, RespawnTimer,
+};
+use crate::replay::{record_inputs, ReplayPlugin, ReplaySchedule};
+use crate::spell::{
+    DamageDealer, DamageSpellProjectileBundle, GameSpells, SpellCasterId, SpellHits,
+    SpellId, SpellLifetime, SpellPlugin,
+};
+use crate::ui::UiPlugin;
+use bevy::prelude::*;
+use bevy::sprite::Material2dPlugin;
+use bevy::window::close_on_esc;
+use bevy_aseprite::AsepritePlugin;
+use bevy_asset_loader::prelude::{LoadingState, LoadingStateAppExt};
+use bevy_ecs_ldtk::prelude::RegisterLdtkObjects;
+use bevy_ecs_ldtk::{LdtkPlugin, LdtkSettings, LdtkWorldBundle, LevelSpawnBehavior};
+use bevy_ggrs::{GGRSPlugin, Rollback, RollbackIdProvider};
+use bevy_simple_2d_outline::OutlineAndTextureMaterial;
+use bevy_tiled_camera::TiledCameraPlugin;
+use iyes_loopless::prelude::{AppLooplessStateExt, ConditionSet, IntoConditionalSystem, NextState};
+use sepax2d::prelude::Circle;
+
+mod assets;
+mod camera;
+mod combat;
+mod game_state;
+pub mod headless;
+mod map;
+mod networking;
+mod physics;
+mod player;
+mod replay;
+mod spell;
+mod ui;
+
+pub const FPS: usize = 60;
+/// The seconds simulated by each frame of the rollback schedule. The simulation can't use the frame
+/// time of the app, or it wouldn't play out the same on every peer and in replays
+pub const FRAME_TIME: f32 = 1.0 / FPS as f32;
+
+/// Runs the game in a window
+pub fn run() {
+    let mut app = App::new();
+
+    GGRSPlugin::<GGRSConfig>::new()
+        // define frequency of rollback game logic update
+        .with_update_frequency(FPS)
+        // define system that returns inputs given a player handle, so GGRS can send the inputs around
+        .with_input_system(input)
+        // register types of components AND resources you want to be rolled back
+        .register_rollback_component::<Transform>()
+        .register_rollback_component::<Movement>()
+        .register_rollback_component::<Health>()
+        .register_rollback_component::<PlayerMovementStats>()
+        .register_rollback_component::<PlayerMovementState>()
+        .register_rollback_component::<AnimationState>()
+        .register_rollback_component::<PlayerCombatState>()
+        .register_rollback_component::<SpellLifetime>()
+        .register_rollback_component::<LavaPool>()
+        .register_rollback_component::<PickupSpawner>()
+        .register_rollback_component::<PlayerBuffs>()
+        .register_rollback_component::<RespawnTimer>()
+        .register_rollback_component::<SpellHits>()
+        //.register_rollback_component::<PlayerCombatState>()
+        //resources
+        .register_rollback_resource::<PlayerSpellBuffer>()
+        .register_rollback_resource::<SafeZone>()
+        .register_rollback_resource::<MatchState>()
+        .register_rollback_resource::<OrbState>()
+        .register_rollback_resource::<RollbackFrame>()
+        //.register_rollback_resource::<NetworkIdProvider>()
+        // these systems will be executed as part of the advance frame update
+        .with_rollback_schedule(rollback_schedule())
+        // make it happen in the bevy app
+        .build(&mut app);
+
+    app.add_loopless_state(GameState::AssetLoading)
+        .add_loading_state(
+            LoadingState::new(GameState::AssetLoading)
+                .continue_to_state(GameState::Menu)
+                .with_collection::<Sprites>()
+                .with_collection::<SpellSprites>()
+                .with_collection::<MenuSprites>()
+                .with_collection::<MapAssets>(),
+        )
+        .add_state(GameState::AssetLoading)
+        //base plugins
+        //.insert_resource(Msaa { samples: 4 })
+        .insert_resource(ClearColor(Color::BLACK))
+        .add_plugins(
+            DefaultPlugins
+                .set(ImagePlugin::default_nearest())
+                .set(WindowPlugin {
+                    window: WindowDescriptor {
+                        title: "Mageling".to_string(),
+                        position: WindowPosition::Automatic,
+                        fit_canvas_to_parent: true,
+                        canvas: Some("#bevy".to_string()),
+                        mode: WindowMode::Fullscreen,
+                        ..default()
+                    },
+                    ..default()
+                }),
+        )
+        .add_plugin(TiledCameraPlugin)
+        .insert_resource(LdtkSettings {
+            // levels are centered on the camera by place_levels instead
+            level_spawn_behavior: LevelSpawnBehavior::UseZeroTranslation,
+            ..default()
+        })
+        .add_plugin(LdtkPlugin)
+        .register_ldtk_entity::<SpawnPointBundle>("SpawnPoint")
+        .register_ldtk_entity::<LavaPoolBundle>("LavaPool")
+        .register_ldtk_entity::<SlowZoneBundle>("SlowZone")
+        .register_ldtk_entity::<PushPadBundle>("PushPad")
+        .register_ldtk_entity::<CrateBundle>("Crate")
+        .register_ldtk_entity::<PickupSpawnerBundle>("PickupSpawner")
+        .register_ldtk_entity::<HillZoneBundle>("Hill")
+        .register_ldtk_entity::<OrbSpawnBundle>("OrbSpawn")
+        .register_ldtk_entity::<OrbGoalBundle>("OrbGoal")
+        // the LevelSelection is only inserted once the players have agreed on a map
+        .init_resource::<MapVote>()
+        .init_resource::<LevelLoaded>()
+        .init_resource::<ModeVote>()
+        .add_plugin(Material2dPlugin::<OutlineAndTextureMaterial>::default())
+        .add_plugin(AsepritePlugin)
+        // base systems
+        .add_system_set(
+            ConditionSet::new()
+                // all the conditions, and any labels/ordering
+                // must be added before adding the systems
+                // (helps avoid confusion and accidents)
+                // (makes it clear they apply to all systems in the set)
+                .run_in_state(GameState::InRound)
+                .label("thing2")
+                //.after("stuff")
+                .with_system(handle_spell_buffer)
+                .with_system(draw_safe_zone)
+                .with_system(update_orb_visual)
+                .into(),
+        )
+        .add_startup_system(setup)
+        .add_enter_system(GameState::Menu, build_map_registry)
+        // leaving a match for the menu cleans up everything the match spawned
+        .add_enter_system(GameState::Menu, teardown_match)
+        .add_enter_system(GameState::Menu, despawn_with::<MapWorld>)
+        .add_enter_system(GameState::Menu, despawn_with::<PlayerId>)
+        .add_enter_system(GameState::Menu, despawn_with::<SpellId>)
+        .add_enter_system(GameState::Menu, despawn_with::<OrbMarker>)
+        .add_system(spawn_players.run_in_state(GameState::BetweenRound))
+        .add_system(follow_match_state)
+        .add_enter_system(GameState::WaitingForPlayers, setup_map)
+        .add_exit_system(GameState::InRound, rotate_map)
+        .add_system(update_animation_state)
+        .add_system(update_mage_colors)
+        .add_system(build_terrain_colliders)
+        .add_system(place_levels)
+        .add_system(track_level_loading)
+        .add_system(assign_map_rollback_ids)
+        .add_system(update_crate_visibility)
+        .add_system(update_pickup_visibility)
+        .add_system(update_player_visibility)
+        .add_system(update_mode_area_visibility)
+        .init_resource::<PlayerSpellBuffer>()
+        .init_resource::<SafeZoneSettings>()
+        .init_resource::<SafeZone>()
+        .init_resource::<MatchSettings>()
+        .init_resource::<MatchState>()
+        .init_resource::<OrbState>()
+        .init_resource::<LocalRematchVote>()
+        .init_resource::<MatchRoster>()
+        .init_resource::<LocalLobbyChoices>();
+    // resources
+    app.insert_resource(LocalPlayer { handle_id: 0 });
+
+    // crate plugins
+    app.add_plugin(CamPlugin)
+        .add_plugin(NetworkPlugin)
+        .add_plugin(ReplayPlugin)
+        .insert_resource(ReplaySchedule(rollback_schedule()))
+        .add_plugin(SpellPlugin)
+        .add_plugin(UiPlugin);
+
+    // Debug stuff
+    //app.add_plugin(RapierDebugRenderPlugin::default());
+    app.add_system(close_on_esc);
+
+    app.run();
+}
+
+/// The systems of the simulation, executed as part of the advance frame update. GGRS runs them during
+/// a match, and the replay player runs them to play a replay back
+fn rollback_schedule() -> Schedule {
+    Schedule::default().with_stage(
+        "ROLLBACK_STAGE",
+        SystemStage::single_threaded()
+            .with_system(read_frame_inputs)
+            .with_system(count_simulated_frames.after(read_frame_inputs))
+            .with_system(record_inputs.after(count_simulated_frames))
+            .with_system(round_system.after(count_simulated_frames))
+            .with_system(move_players.after(round_system))
+            .with_system(handle_spell_casts.after(move_players))
+            .with_system(velocity_system.after(handle_spell_casts))
+            .with_system(push_pad_system.after(velocity_system))
+            .with_system(lava_damage_system.after(push_pad_system))
+            .with_system(safe_zone_system.after(lava_damage_system))
+            .with_system(mode_scoring_system.after(safe_zone_system))
+            .with_system(respawn_system.after(mode_scoring_system))
+            .with_system(pickup_system.after(respawn_system))
+            .with_system(update_player_buffs.after(pickup_system))
+            .with_system(update_dash_info.after(update_player_buffs))
+            // physics stuff - need to be at the end
+            .with_system(clear_correction_system.after(update_dash_info))
+            .with_system(update_movable_system.after(clear_correction_system))
+            .with_system(update_walls_system.after(update_movable_system))
+            .with_system(collision_system.after(update_walls_system))
+            .with_system(update_spell_lifetimes.after(collision_system))
+            .with_system(spell_collision_system.after(update_spell_lifetimes))
+            .with_system(crate_damage_system.after(spell_collision_system))
+            // has to see the final state of the frame
+            .with_system(record_checksum.after(crate_damage_system)),
+    )
+}
+
+/// The game state
+///
+/// - AssetLoading starts at the beginning of every time the app is launched, runs all the asset stuff, and then is never used again
+/// - Menu is used for the main menu and associated places
+/// - WaitingForPlayers is the pregame, menu, lobby. Eg, the player selects find match, it goes to
+///     waiting for players, the player can either quit the matchmaking, or wait to find a match
+/// - BetweenRound is the period between fighting rounds. The players are spawned, the game countdowns till the round starts, the players see the map, etc
+/// - InRound is the actual gameplay. It starts, the players are given control of their characters, and it plays until the round is ended
+/// - PostMatch shows who won the match, the players can ask for a rematch or go back to the menu
+///
+/// Once the session is started the state follows the [`MatchState`] simulated in the rollback schedule
+#[derive(Clone, Eq, PartialEq, Debug, Hash)]
+pub enum GameState {
+    AssetLoading,
+    Menu,
+    WaitingForPlayers,
+    BetweenRound,
+    InRound,
+    PostMatch,
+}
+
+fn spawn_players(
+    sprites: Res<Sprites>,
+    spell_sprites: Res<SpellSprites>,
+
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    asset_server: Res<AssetServer>,
+    roster: Res<MatchRoster>,
+    spawn_points: Query<(&GlobalTransform, &SpawnPoint)>,
+    players: Query<(), With<PlayerId>>,
+    level_loaded: Res<LevelLoaded>,
+) {
+    // wait until the level has been spawned and placed so its spawn points are in the world
+    if !level_loaded.loaded {
+        return;
+    }
+
+    // the players are only spawned once per match, between rounds the round system moves them back
+    if !players.is_empty() {
+        return;
+    }
+
+    // collect and sort for determinism
+    let mut info = spawn_points.iter().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.1);
+
+    let mut spawn_points = vec![];
+
+    for (transform, spawn_point) in info {
+        spawn_points.push((transform.translation(), *spawn_point));
+    }
+
+    for (i, roster_player) in roster.players.iter().enumerate() {
+        let side = roster_player.spawn_side;
+        let spawn_point = spawn_position(&spawn_points, side, roster_player.spawn_slot)
+            .unwrap_or_else(|| {
+                panic!(
+                    "The map doesn't have enough spawn points on side {} to spawn player {}",
+                    side.id, i
+                )
+            });
+        commands.spawn(PlayerBundle::new(
+            i,
+            roster_player,
+            spawn_point,
+            Rollback::new(rip.next_id()),
+            spell_sprites.circle_indicator.clone_weak(),
+            asset_server.load("magelings/Red-Mageling-Run.aseprite"),
+        ));
+    }
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(CameraBundle::default());
+}
+
+fn setup_map(mut commands: Commands, map_assets: Res<MapAssets>) {
+    spawn_map_world(&mut commands, &map_assets);
+}
+
+/// Spawns the LDtk world the levels of the match are spawned in
+pub fn spawn_map_world(commands: &mut Commands, map_assets: &MapAssets) {
+    commands.spawn((
+        LdtkWorldBundle {
+            ldtk_handle: map_assets.map.clone(),
+            level_set: Default::default(),
+            ..default()
+        },
+        MapWorld,
+    ));
+}
+
+#[derive(
+    FromReflect, Reflect, Default, Eq, PartialEq, Debug, PartialOrd, Ord, Copy, Clone, Resource,
+)]
+pub struct NetworkIdProvider {
+    pub current_highest_id: i64,
+}
+
+impl NetworkIdProvider {
+    pub fn next_id(&mut self) -> i64 {
+        self.current_highest_id + 1
+    }
+}
+
+#[derive(
+    FromReflect, Reflect, Default, Eq, PartialEq, Debug, PartialOrd, Ord, Copy, Clone, Component,
+)]
+pub struct NetworkID(i64);
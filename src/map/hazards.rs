@@ -0,0 +1,324 @@
+use crate::game_state::MatchState;
+use crate::map::{
+    enum_field, float_field, int_field, malformed_entity, BlocksMovement, MapCollider, MapRollback,
+    SlowZone,
+};
+use crate::physics::{sweep_movement, ColliderOffset};
+use crate::player::{Health, PlayerId};
+use crate::spell::{DamageDealer, SpellId, SpellLifetime};
+use crate::FRAME_TIME;
+use bevy::asset::{AssetServer, Assets, Handle};
+use bevy::prelude::{
+    default, Bundle, Color, Component, Entity, FromReflect, Image, Query, Reflect, Res, Sprite,
+    SpriteBundle, TextureAtlas, Transform, Vec2, Visibility, With, Without,
+};
+use bevy_ecs_ldtk::ldtk::{LayerInstance, TilesetDefinition};
+use bevy_ecs_ldtk::prelude::LdtkEntity;
+use bevy_ecs_ldtk::EntityInstance;
+use bevy_sepax2d::prelude::{Movable, Sepax};
+use bevy_sepax2d::Convex;
+use sepax2d::prelude::{Circle, AABB};
+use sepax2d::sat_overlap;
+
+/// Damages every player standing in the pool each time the timer reaches the interval
+#[derive(FromReflect, Reflect, Default, Component, Debug, Copy, Clone, PartialEq)]
+pub struct LavaPool {
+    pub damage: u32,
+    pub interval: f32,
+    pub timer: f32,
+}
+
+/// Pushes every player standing on the pad in its direction
+#[derive(Default, Component, Debug, Copy, Clone, PartialEq)]
+pub struct PushPad {
+    pub direction: Vec2,
+    pub strength: f32,
+}
+
+/// A crate that blocks movement until spells bring its [`Health`] down to zero
+#[derive(FromReflect, Reflect, Default, Component, Debug, Copy, Clone, PartialEq)]
+pub struct Crate;
+
+#[derive(Bundle)]
+pub struct LavaPoolBundle {
+    sepax: Sepax,
+    sprite_bundle: SpriteBundle,
+    lava_pool: LavaPool,
+    map_collider: MapCollider,
+    map_rollback: MapRollback,
+}
+
+#[derive(Bundle)]
+pub struct SlowZoneBundle {
+    sepax: Sepax,
+    sprite_bundle: SpriteBundle,
+    slow_zone: SlowZone,
+    map_collider: MapCollider,
+}
+
+#[derive(Bundle)]
+pub struct PushPadBundle {
+    sepax: Sepax,
+    sprite_bundle: SpriteBundle,
+    push_pad: PushPad,
+    map_collider: MapCollider,
+}
+
+#[derive(Bundle)]
+pub struct CrateBundle {
+    sepax: Sepax,
+    collider_offset: ColliderOffset,
+    sprite_bundle: SpriteBundle,
+    health: Health,
+    crate_marker: Crate,
+    map_collider: MapCollider,
+    map_rollback: MapRollback,
+}
+
+impl LdtkEntity for LavaPoolBundle {
+    fn bundle_entity(
+        entity_instance: &EntityInstance,
+        layer_instance: &LayerInstance,
+        tileset: Option<&Handle<Image>>,
+        tileset_definition: Option<&TilesetDefinition>,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) -> Self {
+        let damage = int_field(entity_instance, "Damage")
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+        let interval = float_field(entity_instance, "Interval")
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+
+        LavaPoolBundle {
+            sepax: circle_collider(entity_instance),
+            sprite_bundle: map_sprite(entity_instance, Color::rgba(0.89, 0.23, 0.27, 0.8)),
+            lava_pool: LavaPool {
+                damage: damage.max(0) as u32,
+                interval,
+                timer: 0.0,
+            },
+            map_collider: MapCollider,
+            map_rollback: MapRollback,
+        }
+    }
+}
+
+impl LdtkEntity for SlowZoneBundle {
+    fn bundle_entity(
+        entity_instance: &EntityInstance,
+        layer_instance: &LayerInstance,
+        tileset: Option<&Handle<Image>>,
+        tileset_definition: Option<&TilesetDefinition>,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) -> Self {
+        let speed_multiplier = float_field(entity_instance, "SpeedMultiplier")
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+
+        SlowZoneBundle {
+            sepax: circle_collider(entity_instance),
+            sprite_bundle: map_sprite(entity_instance, Color::rgba(0.39, 0.78, 0.3, 0.5)),
+            slow_zone: SlowZone { speed_multiplier },
+            map_collider: MapCollider,
+        }
+    }
+}
+
+impl LdtkEntity for PushPadBundle {
+    fn bundle_entity(
+        entity_instance: &EntityInstance,
+        layer_instance: &LayerInstance,
+        tileset: Option<&Handle<Image>>,
+        tileset_definition: Option<&TilesetDefinition>,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) -> Self {
+        let direction = enum_field(entity_instance, "Direction")
+            .and_then(parse_direction)
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+        let strength = float_field(entity_instance, "Strength")
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+
+        PushPadBundle {
+            sepax: circle_collider(entity_instance),
+            sprite_bundle: map_sprite(entity_instance, Color::rgba(1.0, 0.9, 0.38, 0.8)),
+            push_pad: PushPad {
+                direction,
+                strength,
+            },
+            map_collider: MapCollider,
+        }
+    }
+}
+
+impl LdtkEntity for CrateBundle {
+    fn bundle_entity(
+        entity_instance: &EntityInstance,
+        layer_instance: &LayerInstance,
+        tileset: Option<&Handle<Image>>,
+        tileset_definition: Option<&TilesetDefinition>,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) -> Self {
+        let health = int_field(entity_instance, "Health")
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error))
+            .max(1) as u32;
+
+        let (width, height) = (entity_instance.width as f32, entity_instance.height as f32);
+        CrateBundle {
+            sepax: Sepax {
+                convex: Convex::AABB(AABB::new((0.0, 0.0), width, height)),
+            },
+            collider_offset: ColliderOffset::centered(width, height),
+            sprite_bundle: map_sprite(entity_instance, Color::rgb(0.72, 0.44, 0.31)),
+            health: Health {
+                max_health: health,
+                current_health: health,
+            },
+            crate_marker: Crate,
+            map_collider: MapCollider,
+            map_rollback: MapRollback,
+        }
+    }
+}
+
+/// Returns the crates that haven't been broken yet, these block movement just like walls do
+pub fn standing_crates<'a>(
+    crates: impl Iterator<Item = (Entity, &'a Sepax, &'a Health)>,
+) -> impl Iterator<Item = (Entity, &'a Sepax)> {
+    crates
+        .filter(|(_, _, health)| health.current_health > 0)
+        .map(|(entity, sepax, _)| (entity, sepax))
+}
+
+/// Parses a value of the LDtk `Direction` enum
+pub fn parse_direction(direction: &str) -> Result<Vec2, String> {
+    match direction {
+        "Up" => Ok(Vec2::Y),
+        "Down" => Ok(Vec2::NEG_Y),
+        "Left" => Ok(Vec2::NEG_X),
+        "Right" => Ok(Vec2::X),
+        other => Err(format!("{:?} is not a valid Direction", other)),
+    }
+}
+
+pub(crate) fn circle_collider(entity_instance: &EntityInstance) -> Sepax {
+    Sepax {
+        convex: Convex::Circle(Circle {
+            position: (0.0, 0.0),
+            radius: entity_instance.width.min(entity_instance.height) as f32 / 2.0,
+        }),
+    }
+}
+
+pub(crate) fn map_sprite(entity_instance: &EntityInstance, color: Color) -> SpriteBundle {
+    SpriteBundle {
+        sprite: Sprite {
+            color,
+            custom_size: Some(Vec2::new(
+                entity_instance.width as f32,
+                entity_instance.height as f32,
+            )),
+            ..default()
+        },
+        ..default()
+    }
+}
+
+/// Damages every player inside of a lava pool each time the pool ticks
+pub fn lava_damage_system(
+    mut lava_pools: Query<(Entity, &Sepax, &mut LavaPool), Without<PlayerId>>,
+    mut players: Query<(&PlayerId, &Sepax, &mut Health)>,
+    match_state: Res<MatchState>,
+) {
+    if !match_state.is_fighting() {
+        return;
+    }
+
+    // collect and sort for determinism
+    let mut info = lava_pools.iter_mut().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+
+    let mut players = players.iter_mut().collect::<Vec<_>>();
+    players.sort_by_key(|x| x.0);
+
+    for (_, lava_sepax, mut lava_pool) in info {
+        lava_pool.timer += FRAME_TIME;
+        if lava_pool.timer < lava_pool.interval {
+            continue;
+        }
+        lava_pool.timer -= lava_pool.interval;
+
+        for (_, player_sepax, health) in players.iter_mut() {
+            if sat_overlap(lava_sepax.shape(), player_sepax.shape()) {
+                health.current_health = health.current_health.saturating_sub(lava_pool.damage);
+            }
+        }
+    }
+}
+
+/// Pushes every player standing on a push pad, sweeping them against walls like normal movement
+pub fn push_pad_system(
+    push_pads: Query<(Entity, &Sepax, &PushPad), Without<Movable>>,
+    mut players: Query<(Entity, &mut Sepax, &mut Transform), (With<PlayerId>, With<Movable>)>,
+    walls: Query<(Entity, &Sepax), (With<BlocksMovement>, Without<Movable>)>,
+    crates: Query<(Entity, &Sepax, &Health), (With<Crate>, Without<Movable>)>,
+    match_state: Res<MatchState>,
+) {
+    if !match_state.is_fighting() {
+        return;
+    }
+
+    // collect and sort for determinism
+    let mut push_pads = push_pads.iter().collect::<Vec<_>>();
+    push_pads.sort_by_key(|x| x.0);
+
+    let mut walls = walls.iter().chain(standing_crates(crates.iter())).collect::<Vec<_>>();
+    walls.sort_by_key(|x| x.0);
+    let walls = walls.into_iter().map(|x| x.1).collect::<Vec<_>>();
+
+    let mut info = players.iter_mut().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+
+    for (_, mut sepax, mut transform) in info {
+        for (_, pad_sepax, push_pad) in push_pads.iter() {
+            if sat_overlap(pad_sepax.shape(), sepax.shape()) {
+                let delta = push_pad.direction * push_pad.strength * FRAME_TIME;
+                sweep_movement(&mut sepax, &mut transform, delta, &walls);
+            }
+        }
+    }
+}
+
+/// Damages crates caught in exploding spells
+pub fn crate_damage_system(
+    spells: Query<(Entity, &Sepax, &DamageDealer, &SpellLifetime), With<SpellId>>,
+    mut crates: Query<(Entity, &Sepax, &mut Health), (With<Crate>, Without<SpellId>)>,
+) {
+    // collect and sort for determinism
+    let mut spells = spells.iter().collect::<Vec<_>>();
+    spells.sort_by_key(|x| x.0);
+
+    let mut info = crates.iter_mut().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+
+    for (_, spell_sepax, damage, lifetime) in spells {
+        // spells only deal damage on the frame they go off
+        if !lifetime.is_going_off() {
+            continue;
+        }
+        for (_, crate_sepax, health) in info.iter_mut() {
+            if health.current_health > 0 && sat_overlap(spell_sepax.shape(), crate_sepax.shape()) {
+                health.current_health = health.current_health.saturating_sub(damage.damage_amount);
+            }
+        }
+    }
+}
+
+/// Hides crates once they are broken. Broken crates are kept around instead of being despawned so
+/// that rolling back to before they broke brings them back.
+pub fn update_crate_visibility(mut crates: Query<(&Health, &mut Visibility), With<Crate>>) {
+    for (health, mut visibility) in crates.iter_mut() {
+        visibility.is_visible = health.current_health > 0;
+    }
+}
@@ -1,53 +1,202 @@
-﻿use crate::player::{PlayerId, TeamId};
+﻿use crate::assets::MapAssets;
+use crate::player::TeamId;
 use bevy::asset::{AssetServer, Assets, Handle};
-use bevy::log::info;
-use bevy::prelude::{Bundle, Component, IVec2, Image, TextureAtlas, TransformBundle, FromReflect, Reflect};
-use bevy_ecs_ldtk::ldtk::{FieldInstance, LayerInstance, TilesetDefinition};
+use bevy::prelude::{
+    Added, BuildChildren, Bundle, Commands, Component, Entity, FromReflect, IVec2, Image, Or,
+    Parent, Query, Reflect, Res, ResMut, Resource, TextureAtlas, Transform, TransformBundle, Vec3,
+    With, Without,
+};
+use bevy::utils::{HashMap, HashSet};
+use bevy_ecs_ldtk::ldtk::{FieldValue, LayerInstance, TilesetDefinition};
 use bevy_ecs_ldtk::prelude::LdtkEntity;
-use bevy_ecs_ldtk::{EntityInstance, IntGridCell};
+use bevy_ecs_ldtk::{
+    EntityInstance, GridCoords, IntGridCell, LdtkAsset, LdtkLevel, LevelSelection,
+};
+use bevy_ggrs::{Rollback, RollbackIdProvider};
 use bevy_sepax2d::prelude::Sepax;
 use bevy_sepax2d::Convex;
 use sepax2d::prelude::AABB;
 
-#[derive(Bundle)]
-pub struct WallCollisions {
-    sepax: Sepax,
-    transform_bundle: TransformBundle,
-    wall: Wall,
-}
+pub mod hazards;
+pub mod pickups;
 
+/// A solid wall of the map, as opposed to pits and water that only block movement
 #[derive(Component)]
 pub struct Wall;
 
-impl LdtkEntity for WallCollisions {
-    fn bundle_entity(
-        entity_instance: &EntityInstance,
-        layer_instance: &LayerInstance,
-        tileset: Option<&Handle<Image>>,
-        tileset_definition: Option<&TilesetDefinition>,
-        asset_server: &AssetServer,
-        texture_atlases: &mut Assets<TextureAtlas>,
-    ) -> Self {
-        WallCollisions {
-            sepax: Sepax {
-                convex: Convex::AABB(AABB::new((0.0, 0.0), 16.0, 16.0)),
-            },
-            transform_bundle: Default::default(),
-            wall: Wall,
+/// Blocks the movement of players. Added to walls, pits and water
+#[derive(Component)]
+pub struct BlocksMovement;
+
+/// Slows down any player standing inside of it
+#[derive(Component, Debug, Copy, Clone, PartialEq)]
+pub struct SlowZone {
+    pub speed_multiplier: f32,
+}
+
+/// All the levels in the LDtk project that can be played on, in the order they appear in the project
+#[derive(Resource, Default, Debug, Clone, PartialEq)]
+pub struct MapRegistry {
+    pub maps: Vec<MapInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapInfo {
+    pub identifier: String,
+    pub level_index: usize,
+}
+
+/// The level the local player voted to play on
+#[derive(Resource, Default, Debug, Copy, Clone, PartialEq)]
+pub struct MapVote {
+    pub level: usize,
+}
+
+/// The order that levels are played in for the current match. Starts on the level that won the map
+/// vote and then cycles through every other level in the registry, one per round.
+#[derive(Resource, Default, Debug, Clone, PartialEq)]
+pub struct MapRotation {
+    pub levels: Vec<usize>,
+    pub current: usize,
+}
+
+impl MapRotation {
+    pub fn new(registry: &MapRegistry, first_level: usize) -> Self {
+        let mut levels = registry
+            .maps
+            .iter()
+            .map(|map| map.level_index)
+            .collect::<Vec<_>>();
+        let start = levels
+            .iter()
+            .position(|level| *level == first_level)
+            .unwrap_or(0);
+        levels.rotate_left(start);
+
+        MapRotation { levels, current: 0 }
+    }
+
+    pub fn current_level(&self) -> usize {
+        self.levels.get(self.current).copied().unwrap_or(0)
+    }
+
+    /// Moves on to the next level of the rotation, wrapping back around to the first one
+    pub fn advance(&mut self) -> usize {
+        if !self.levels.is_empty() {
+            self.current = (self.current + 1) % self.levels.len();
         }
+        self.current_level()
     }
 }
 
+/// Picks the level with the most votes. Ties go to the level that comes first in the project so
+/// every peer picks the same level from the same set of votes.
+pub fn resolve_map_vote(votes: &[usize]) -> usize {
+    let mut tally: HashMap<usize, usize> = HashMap::default();
+    for vote in votes {
+        *tally.entry(*vote).or_default() += 1;
+    }
+
+    let mut tally = tally.into_iter().collect::<Vec<_>>();
+    tally.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    tally.first().map(|(level, _)| *level).unwrap_or(0)
+}
+
+/// Builds the [`MapRegistry`] from the levels in the LDtk project
+pub fn build_map_registry(
+    mut commands: Commands,
+    map_assets: Res<MapAssets>,
+    ldtk_assets: Res<Assets<LdtkAsset>>,
+) {
+    let ldtk_asset = ldtk_assets
+        .get(&map_assets.map)
+        .expect("The map is loaded in the asset loading state");
+
+    let maps = ldtk_asset
+        .project
+        .levels
+        .iter()
+        .enumerate()
+        .map(|(level_index, level)| MapInfo {
+            identifier: level.identifier.clone(),
+            level_index,
+        })
+        .collect();
+
+    commands.insert_resource(MapRegistry { maps });
+}
+
+/// Switches the level to the next one in the rotation once a round is over
+pub fn rotate_map(mut commands: Commands, rotation: Option<ResMut<MapRotation>>) {
+    if let Some(mut rotation) = rotation {
+        let level = rotation.advance();
+        commands.insert_resource(LevelSelection::Index(level));
+    }
+}
+
+/// Marks the LDtk world the levels are spawned in, so it can be despawned when the match ends
+#[derive(Component)]
+pub struct MapWorld;
+
+/// Marks a level that has already been centered in the world by [`place_levels`]
+#[derive(Component)]
+pub struct LevelPlaced;
+
+/// Any collider that is part of the map, its shape follows the global position of the entity
+#[derive(Component)]
+pub struct MapCollider;
+
+/// Marks a map entity with state that has to be rolled back, it is given a rollback id by
+/// [`assign_map_rollback_ids`] once its level is spawned
+#[derive(Component)]
+pub struct MapRollback;
+
+/// The size in pixels of a single cell of the `Collisions` IntGrid layer in the LDtk map
+pub const TERRAIN_CELL_SIZE: f32 = 16.0;
+
+/// The types of terrain that can be painted into the `Collisions` IntGrid layer of the LDtk map
+#[derive(Eq, PartialEq, Debug, Copy, Clone, Hash)]
+pub enum TerrainType {
+    Wall,
+    Pit,
+    Water,
+    SlowZone,
+}
+
+impl TerrainType {
+    /// Returns the terrain type for the given IntGrid value, or none if the value has no collision
+    pub fn from_int_grid_value(value: i32) -> Option<TerrainType> {
+        match value {
+            1 => Some(TerrainType::Wall),
+            2 => Some(TerrainType::Pit),
+            3 => Some(TerrainType::Water),
+            4 => Some(TerrainType::SlowZone),
+            _ => None,
+        }
+    }
+}
+
+/// A collider built from the `Collisions` IntGrid layer in [`build_terrain_colliders`]
+#[derive(Component, Debug, Copy, Clone, PartialEq)]
+pub struct TerrainCollider {
+    pub terrain_type: TerrainType,
+}
+
 #[derive(Bundle)]
 pub struct SpawnPointBundle {
     transform_bundle: TransformBundle,
     pub spawn_point: SpawnPoint,
 }
 
+/// A place in the level where players of the given team are spawned. Spawn points are handed out
+/// to the players of a team in order of their index
 #[derive(
     FromReflect, Reflect, Default, Eq, PartialEq, Debug, PartialOrd, Ord, Copy, Clone, Component,
 )]
-pub struct SpawnPoint(pub TeamId);
+pub struct SpawnPoint {
+    pub team_id: TeamId,
+    pub index: usize,
+}
 
 impl LdtkEntity for SpawnPointBundle {
     fn bundle_entity(
@@ -58,26 +207,300 @@ impl LdtkEntity for SpawnPointBundle {
         asset_server: &AssetServer,
         texture_atlases: &mut Assets<TextureAtlas>,
     ) -> Self {
-        let team_id = match &entity_instance.field_instances[0] {
-            FieldInstance {
-                identifier,
-                tile,
-                field_instance_type,
-                value,
-                def_uid,
-                real_editor_values,
-            } => {
-                info!("{:?}", field_instance_type);
-                if field_instance_type == "Team0" {
-                    TeamId { id: 0 }
-                } else {
-                    TeamId { id: 1 }
-                }
-            }
-        };
+        // bad spawn data would leave players spawning on the wrong team or not at all so its
+        // better to stop right here and tell the designer what is wrong
+        let spawn_point = parse_spawn_point(entity_instance)
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+
         SpawnPointBundle {
             transform_bundle: Default::default(),
-            spawn_point: SpawnPoint(team_id),
+            spawn_point,
+        }
+    }
+}
+
+/// Returns where the player in the given slot of a team spawns. The spawn points have to be sorted by
+/// team and index, so the slot is the nth spawn point of the team
+pub fn spawn_position(
+    spawn_points: &[(Vec3, SpawnPoint)],
+    team_id: TeamId,
+    slot: usize,
+) -> Option<Vec3> {
+    spawn_points
+        .iter()
+        .filter(|(_, spawn_point)| spawn_point.team_id == team_id)
+        .nth(slot)
+        .map(|(translation, _)| *translation)
+}
+
+/// Reads the `Team` enum field and `Index` int field of a SpawnPoint entity
+pub fn parse_spawn_point(entity_instance: &EntityInstance) -> Result<SpawnPoint, String> {
+    let team_id = parse_team(enum_field(entity_instance, "Team")?)?;
+
+    let index = int_field(entity_instance, "Index")?;
+    if index < 0 {
+        return Err(format!("the Index field must not be negative but is {}", index));
+    }
+
+    Ok(SpawnPoint {
+        team_id,
+        index: index as usize,
+    })
+}
+
+/// Parses a value of the LDtk `Team` enum, eg `Team0` or `Team3`, into a [`TeamId`]
+pub fn parse_team(team: &str) -> Result<TeamId, String> {
+    team.strip_prefix("Team")
+        .and_then(|id| id.parse::<usize>().ok())
+        .map(|id| TeamId { id })
+        .ok_or_else(|| format!("{:?} is not a valid Team, expected Team followed by a number", team))
+}
+
+/// Stops the game with a message pointing the designer at the broken entity in LDtk
+pub(crate) fn malformed_entity(
+    entity_instance: &EntityInstance,
+    layer_instance: &LayerInstance,
+    error: String,
+) -> ! {
+    panic!(
+        "Malformed {} at {:?} (iid {}) in layer {}: {}",
+        entity_instance.identifier,
+        entity_instance.px,
+        entity_instance.iid,
+        layer_instance.identifier,
+        error
+    )
+}
+
+fn find_field<'a>(entity_instance: &'a EntityInstance, identifier: &str) -> Result<&'a FieldValue, String> {
+    entity_instance
+        .field_instances
+        .iter()
+        .find(|field| field.identifier == identifier)
+        .map(|field| &field.value)
+        .ok_or_else(|| format!("missing the {} field", identifier))
+}
+
+pub(crate) fn int_field(entity_instance: &EntityInstance, identifier: &str) -> Result<i32, String> {
+    match find_field(entity_instance, identifier)? {
+        FieldValue::Int(Some(value)) => Ok(*value),
+        FieldValue::Int(None) => Err(format!("the {} field has no value", identifier)),
+        other => Err(format!("the {} field should be an int but is {:?}", identifier, other)),
+    }
+}
+
+pub(crate) fn float_field(entity_instance: &EntityInstance, identifier: &str) -> Result<f32, String> {
+    match find_field(entity_instance, identifier)? {
+        FieldValue::Float(Some(value)) => Ok(*value),
+        FieldValue::Float(None) => Err(format!("the {} field has no value", identifier)),
+        other => Err(format!("the {} field should be a float but is {:?}", identifier, other)),
+    }
+}
+
+pub(crate) fn enum_field<'a>(entity_instance: &'a EntityInstance, identifier: &str) -> Result<&'a str, String> {
+    match find_field(entity_instance, identifier)? {
+        FieldValue::Enum(Some(value)) => Ok(value.as_str()),
+        FieldValue::Enum(None) => Err(format!("the {} field has no value", identifier)),
+        other => Err(format!("the {} field should be an enum but is {:?}", identifier, other)),
+    }
+}
+
+/// Centers every newly loaded level on the world origin, which is where the camera is looking, using
+/// the dimensions of the level from LDtk. Each level is only ever placed once so it stays put no
+/// matter how many rounds are played on it.
+pub fn place_levels(
+    mut commands: Commands,
+    mut levels: Query<(Entity, &mut Transform, &Handle<LdtkLevel>), Without<LevelPlaced>>,
+    level_assets: Res<Assets<LdtkLevel>>,
+) {
+    for (entity, mut transform, level_handle) in levels.iter_mut() {
+        // the level asset may still be loading, in that case it gets placed on a later frame
+        if let Some(ldtk_level) = level_assets.get(level_handle) {
+            transform.translation.x = -(ldtk_level.level.px_wid as f32) / 2.0;
+            transform.translation.y = -(ldtk_level.level.px_hei as f32) / 2.0;
+            commands.entity(entity).insert(LevelPlaced);
         }
     }
 }
+
+/// Whether the level is fully in the world: spawned, centered, with its colliders built and the
+/// global transforms of its entities up to date. Updated every frame by [`track_level_loading`]
+#[derive(Resource, Default, Debug, Copy, Clone, PartialEq)]
+pub struct LevelLoaded {
+    pub loaded: bool,
+}
+
+/// Keeps [`LevelLoaded`] up to date. The level is spawned asynchronously once its asset is loaded,
+/// so this is checked every frame instead of waiting for a one-off LDtk event that may have already
+/// gone by. After the map rotation swaps the level, the old level doesn't count as loaded
+pub fn track_level_loading(
+    mut level_loaded: ResMut<LevelLoaded>,
+    selection: Option<Res<LevelSelection>>,
+    registry: Option<Res<MapRegistry>>,
+    level_assets: Res<Assets<LdtkLevel>>,
+    levels: Query<(&Handle<LdtkLevel>, Option<&LevelPlaced>)>,
+    spawn_points: Query<(), With<SpawnPoint>>,
+    added: Query<(), Or<(Added<LevelPlaced>, Added<SpawnPoint>, Added<IntGridCell>)>>,
+) {
+    let (selection, registry) = match (selection, registry) {
+        (Some(selection), Some(registry)) => (selection, registry),
+        _ => {
+            level_loaded.loaded = false;
+            return;
+        }
+    };
+
+    let is_selected = |handle: &Handle<LdtkLevel>| {
+        level_assets
+            .get(handle)
+            .and_then(|ldtk_level| {
+                registry
+                    .maps
+                    .iter()
+                    .find(|map| map.identifier == ldtk_level.level.identifier)
+                    .map(|map| selection.is_match(&map.level_index, &ldtk_level.level))
+            })
+            .unwrap_or(false)
+    };
+
+    // anything added since the last frame only gets its colliders and global transform at the end of
+    // the frame it was added on
+    level_loaded.loaded = !levels.is_empty()
+        && levels
+            .iter()
+            .all(|(handle, placed)| placed.is_some() && is_selected(handle))
+        && !spawn_points.is_empty()
+        && added.is_empty();
+}
+
+/// Gives a rollback id to every [`MapRollback`] entity of a newly spawned level. Entities are ordered
+/// by their position in the level so the ids don't depend on the order LDtk spawned them in.
+pub fn assign_map_rollback_ids(
+    mut commands: Commands,
+    mut rip: ResMut<RollbackIdProvider>,
+    entities: Query<(Entity, &Transform), (With<MapRollback>, Without<Rollback>)>,
+) {
+    let mut info = entities.iter().collect::<Vec<_>>();
+    info.sort_by(|a, b| {
+        let a = a.1.translation;
+        let b = b.1.translation;
+        a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y))
+    });
+
+    for (entity, _) in info {
+        commands.entity(entity).insert(Rollback::new(rip.next_id()));
+    }
+}
+
+/// Builds the colliders of a freshly loaded level from its `Collisions` IntGrid layer. Neighbouring
+/// cells of the same terrain are merged into as few large rectangles as possible, which is cheaper to
+/// check and removes the seams between tiles that players would otherwise catch on.
+///
+/// Because collision comes straight from the IntGrid, repainting the layer in LDtk is all it takes
+/// to change the collision of a level.
+pub fn build_terrain_colliders(
+    mut commands: Commands,
+    cells: Query<(&GridCoords, &IntGridCell, Option<&Parent>), Added<IntGridCell>>,
+) {
+    // cells are grouped by their parent layer as their colliders are placed relative to it
+    let mut layers: HashMap<(Option<Entity>, TerrainType), Vec<IVec2>> = HashMap::default();
+    for (grid_coords, int_grid_cell, parent) in cells.iter() {
+        let terrain_type = match TerrainType::from_int_grid_value(int_grid_cell.value) {
+            Some(terrain_type) => terrain_type,
+            None => continue,
+        };
+        layers
+            .entry((parent.map(|parent| parent.get()), terrain_type))
+            .or_default()
+            .push(IVec2::new(grid_coords.x, grid_coords.y));
+    }
+
+    for ((parent, terrain_type), cells) in layers {
+        for (min, size) in merge_cells(&cells) {
+            // an AABB is positioned by its minimum corner, which is the corner of its minimum cell
+            let translation = Vec3::new(
+                min.x as f32 * TERRAIN_CELL_SIZE,
+                min.y as f32 * TERRAIN_CELL_SIZE,
+                0.0,
+            );
+            let mut collider = commands.spawn((
+                Sepax {
+                    convex: Convex::AABB(AABB::new(
+                        (translation.x, translation.y),
+                        size.x as f32 * TERRAIN_CELL_SIZE,
+                        size.y as f32 * TERRAIN_CELL_SIZE,
+                    )),
+                },
+                TransformBundle::from_transform(Transform::from_translation(translation)),
+                TerrainCollider { terrain_type },
+                MapCollider,
+            ));
+
+            match terrain_type {
+                TerrainType::Wall => {
+                    collider.insert((Wall, BlocksMovement));
+                }
+                TerrainType::Pit | TerrainType::Water => {
+                    collider.insert(BlocksMovement);
+                }
+                TerrainType::SlowZone => {
+                    collider.insert(SlowZone {
+                        speed_multiplier: 0.5,
+                    });
+                }
+            }
+
+            let collider = collider.id();
+            if let Some(parent) = parent {
+                commands.entity(parent).add_child(collider);
+            }
+        }
+    }
+}
+
+/// Greedily merges a set of grid cells into rectangles, returned as their minimum cell and their size
+/// in cells. Rows are grown first and then extended upwards for as long as the full row is filled.
+///
+/// The output only depends on the set of cells, not their order, so every peer builds the same colliders.
+pub fn merge_cells(cells: &[IVec2]) -> Vec<(IVec2, IVec2)> {
+    let mut sorted = cells.to_vec();
+    sorted.sort_by_key(|cell| (cell.y, cell.x));
+
+    let cells: HashSet<IVec2> = sorted.iter().copied().collect();
+    let mut used: HashSet<IVec2> = HashSet::default();
+    let mut rects = vec![];
+
+    for start in sorted {
+        if used.contains(&start) {
+            continue;
+        }
+
+        let mut width = 1;
+        while cells.contains(&(start + IVec2::new(width, 0)))
+            && !used.contains(&(start + IVec2::new(width, 0)))
+        {
+            width += 1;
+        }
+
+        let mut height = 1;
+        'grow: loop {
+            for x in 0..width {
+                let cell = start + IVec2::new(x, height);
+                if !cells.contains(&cell) || used.contains(&cell) {
+                    break 'grow;
+                }
+            }
+            height += 1;
+        }
+
+        for y in 0..height {
+            for x in 0..width {
+                used.insert(start + IVec2::new(x, y));
+            }
+        }
+
+        rects.push((start, IVec2::new(width, height)));
+    }
+
+    rects
+}
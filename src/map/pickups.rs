@@ -0,0 +1,184 @@
+use crate::game_state::MatchState;
+use crate::map::hazards::{circle_collider, map_sprite};
+use crate::map::{enum_field, float_field, malformed_entity, MapCollider, MapRollback};
+use crate::player::{Health, PlayerBuffs, PlayerId, PlayerMovementState};
+use crate::FRAME_TIME;
+use bevy::asset::{AssetServer, Assets, Handle};
+use bevy::prelude::{
+    Bundle, Color, Component, Entity, FromReflect, Image, Query, Reflect, Res, SpriteBundle,
+    TextureAtlas, Visibility, Without,
+};
+use bevy_ecs_ldtk::ldtk::{LayerInstance, TilesetDefinition};
+use bevy_ecs_ldtk::prelude::LdtkEntity;
+use bevy_ecs_ldtk::EntityInstance;
+use bevy_sepax2d::prelude::Sepax;
+use sepax2d::sat_overlap;
+
+/// How much health a health orb gives back
+pub const HEALTH_ORB_HEAL: u32 = 30;
+/// How much more damage spells deal while the damage buff is active
+pub const DAMAGE_BUFF_MULTIPLIER: f32 = 1.5;
+/// How many seconds the damage buff lasts
+pub const DAMAGE_BUFF_DURATION: f32 = 8.0;
+
+#[derive(FromReflect, Reflect, Default, Eq, PartialEq, Debug, Copy, Clone)]
+pub enum PickupKind {
+    #[default]
+    HealthOrb,
+    CooldownReset,
+    DamageBuff,
+}
+
+impl PickupKind {
+    /// Parses a value of the LDtk `PickupKind` enum
+    pub fn from_ldtk(kind: &str) -> Result<PickupKind, String> {
+        match kind {
+            "HealthOrb" => Ok(PickupKind::HealthOrb),
+            "CooldownReset" => Ok(PickupKind::CooldownReset),
+            "DamageBuff" => Ok(PickupKind::DamageBuff),
+            other => Err(format!("{:?} is not a valid PickupKind", other)),
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            PickupKind::HealthOrb => Color::rgb(0.39, 0.78, 0.3),
+            PickupKind::CooldownReset => Color::rgb(0.17, 0.91, 0.96),
+            PickupKind::DamageBuff => Color::rgb(1.0, 0.68, 0.2),
+        }
+    }
+}
+
+/// A spot on the map where a pickup of the given kind appears. Once collected the spawner counts
+/// down its respawn timer before the pickup is available again
+#[derive(FromReflect, Reflect, Default, Component, Debug, Copy, Clone, PartialEq)]
+pub struct PickupSpawner {
+    pub kind: PickupKind,
+    pub respawn_time: f32,
+    pub respawn_timer: f32,
+    pub available: bool,
+}
+
+#[derive(Bundle)]
+pub struct PickupSpawnerBundle {
+    sepax: Sepax,
+    sprite_bundle: SpriteBundle,
+    pickup_spawner: PickupSpawner,
+    map_collider: MapCollider,
+    map_rollback: MapRollback,
+}
+
+impl LdtkEntity for PickupSpawnerBundle {
+    fn bundle_entity(
+        entity_instance: &EntityInstance,
+        layer_instance: &LayerInstance,
+        tileset: Option<&Handle<Image>>,
+        tileset_definition: Option<&TilesetDefinition>,
+        asset_server: &AssetServer,
+        texture_atlases: &mut Assets<TextureAtlas>,
+    ) -> Self {
+        let kind = enum_field(entity_instance, "Kind")
+            .and_then(PickupKind::from_ldtk)
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+        let respawn_time = float_field(entity_instance, "RespawnTime")
+            .unwrap_or_else(|error| malformed_entity(entity_instance, layer_instance, error));
+
+        PickupSpawnerBundle {
+            sepax: circle_collider(entity_instance),
+            sprite_bundle: map_sprite(entity_instance, kind.color()),
+            pickup_spawner: PickupSpawner {
+                kind,
+                respawn_time,
+                respawn_timer: 0.0,
+                available: true,
+            },
+            map_collider: MapCollider,
+            map_rollback: MapRollback,
+        }
+    }
+}
+
+/// Counts down the respawn timers of collected pickups and gives available pickups to the first
+/// player touching them. Players are checked in handle order so every peer agrees on who got it
+pub fn pickup_system(
+    mut spawners: Query<(Entity, &Sepax, &mut PickupSpawner), Without<PlayerId>>,
+    mut players: Query<(
+        &PlayerId,
+        &Sepax,
+        &mut Health,
+        &mut PlayerMovementState,
+        &mut PlayerBuffs,
+    )>,
+    match_state: Res<MatchState>,
+) {
+    if !match_state.is_fighting() {
+        return;
+    }
+
+    // collect and sort for determinism
+    let mut info = spawners.iter_mut().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+
+    let mut players = players.iter_mut().collect::<Vec<_>>();
+    players.sort_by_key(|x| x.0);
+
+    for (_, spawner_sepax, mut spawner) in info {
+        if !spawner.available {
+            spawner.respawn_timer -= FRAME_TIME;
+            if spawner.respawn_timer <= 0.0 {
+                spawner.available = true;
+            }
+            continue;
+        }
+
+        for (_, player_sepax, health, movement_state, buffs) in players.iter_mut() {
+            if health.current_health == 0
+                || !sat_overlap(spawner_sepax.shape(), player_sepax.shape())
+            {
+                continue;
+            }
+
+            match spawner.kind {
+                PickupKind::HealthOrb => {
+                    health.current_health =
+                        (health.current_health + HEALTH_ORB_HEAL).min(health.max_health);
+                }
+                PickupKind::CooldownReset => {
+                    movement_state.can_dash = true;
+                    movement_state.dash_cooldown = 0.0;
+                }
+                PickupKind::DamageBuff => {
+                    buffs.damage_multiplier = DAMAGE_BUFF_MULTIPLIER;
+                    buffs.damage_buff_remaining = DAMAGE_BUFF_DURATION;
+                }
+            }
+
+            spawner.available = false;
+            spawner.respawn_timer = spawner.respawn_time;
+            break;
+        }
+    }
+}
+
+/// Counts down temporary buffs and removes them once they run out
+pub fn update_player_buffs(mut players: Query<(&PlayerId, &mut PlayerBuffs)>) {
+    // collect and sort for determinism
+    let mut info = players.iter_mut().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+
+    for (_, mut buffs) in info {
+        if buffs.damage_buff_remaining > 0.0 {
+            buffs.damage_buff_remaining -= FRAME_TIME;
+            if buffs.damage_buff_remaining <= 0.0 {
+                *buffs = PlayerBuffs::default();
+            }
+        }
+    }
+}
+
+/// Hides pickups while they are waiting to respawn
+pub fn update_pickup_visibility(mut spawners: Query<(&PickupSpawner, &mut Visibility)>) {
+    for (spawner, mut visibility) in spawners.iter_mut() {
+        visibility.is_visible = spawner.available;
+    }
+}
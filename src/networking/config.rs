@@ -0,0 +1,187 @@
+use crate::game_state::MatchFormat;
+use bevy::log::warn;
+use bevy::prelude::Resource;
+use serde::Deserialize;
+use std::fs;
+
+/// The config file read when no other file is given with `--config` or `MG23_CONFIG`
+pub const DEFAULT_CONFIG_PATH: &str = "mg23.toml";
+
+/// How to reach the matchmaking server and how to run the GGRS session.
+///
+/// Read from, in order, each overriding the previous one:
+/// - the config file, `mg23.toml` or the file given with `--config <path>` or `MG23_CONFIG`
+/// - the environment variables `MG23_SIGNALING_URL`, `MG23_ROOM`, `MG23_PLAYERS`,
+///   `MG23_INPUT_DELAY`, `MG23_ADAPTIVE_INPUT_DELAY`, `MG23_MAX_PREDICTION`,
+///   `MG23_MATCHMAKING_TIMEOUT` and `MG23_DISCONNECT_TIMEOUT`
+/// - the command line flags `--signaling-url`, `--room`, `--players`, `--input-delay`,
+///   `--adaptive-input-delay`, `--max-prediction`, `--matchmaking-timeout` and
+///   `--disconnect-timeout`, given either as `--flag value` or `--flag=value`
+///
+/// The config file uses the same names as the fields, eg `signaling_url = "wss://example.com"`
+#[derive(Resource, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    /// The matchbox signaling server, `ws://` or `wss://`. A url without a scheme uses `ws://`
+    pub signaling_url: String,
+    /// Prefixes the rooms on the signaling server, so different builds of the game don't get matched
+    /// with each other
+    pub room: String,
+    /// Preselects the match format with this many players in the menu
+    pub players: Option<usize>,
+    /// Frames of input delay, the least input delay when it is adaptive
+    pub input_delay: usize,
+    /// Pick the input delay for each match from the round trip time to the other peers
+    pub adaptive_input_delay: bool,
+    /// How many frames GGRS may predict ahead of the inputs of the other peers
+    pub max_prediction: usize,
+    /// Seconds to wait for another player before giving up on the matchmaking server
+    pub matchmaking_timeout: f32,
+    /// Seconds without hearing from a peer before it is disconnected and forfeits the match
+    pub disconnect_timeout: f32,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            signaling_url: "ws://172.124.208.194:6500".to_string(),
+            room: "mg23".to_string(),
+            players: None,
+            input_delay: 2,
+            adaptive_input_delay: false,
+            max_prediction: 12,
+            matchmaking_timeout: 30.0,
+            disconnect_timeout: 5.0,
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Reads the config from the config file, the environment and the command line. Invalid values
+    /// are warned about and ignored
+    pub fn load() -> Self {
+        let args = std::env::args().skip(1).collect::<Vec<_>>();
+        let path = flag_value(&args, "config")
+            .or_else(|| std::env::var("MG23_CONFIG").ok())
+            .unwrap_or_else(|| DEFAULT_CONFIG_PATH.to_string());
+
+        let mut config = match fs::read_to_string(&path) {
+            Ok(file) => toml::from_str(&file).unwrap_or_else(|error| {
+                warn!("Ignoring the invalid config file {}: {}", path, error);
+                NetworkConfig::default()
+            }),
+            // not having a config file is fine
+            Err(_) => NetworkConfig::default(),
+        };
+
+        for (field, variable) in [
+            ("signaling-url", "MG23_SIGNALING_URL"),
+            ("room", "MG23_ROOM"),
+            ("players", "MG23_PLAYERS"),
+            ("input-delay", "MG23_INPUT_DELAY"),
+            ("adaptive-input-delay", "MG23_ADAPTIVE_INPUT_DELAY"),
+            ("max-prediction", "MG23_MAX_PREDICTION"),
+            ("matchmaking-timeout", "MG23_MATCHMAKING_TIMEOUT"),
+            ("disconnect-timeout", "MG23_DISCONNECT_TIMEOUT"),
+        ] {
+            if let Ok(value) = std::env::var(variable) {
+                config.set(field, &value);
+            }
+        }
+
+        for field in [
+            "signaling-url",
+            "room",
+            "players",
+            "input-delay",
+            "adaptive-input-delay",
+            "max-prediction",
+            "matchmaking-timeout",
+            "disconnect-timeout",
+        ] {
+            if let Some(value) = flag_value(&args, field) {
+                config.set(field, &value);
+            }
+        }
+
+        config.signaling_url = normalize_signaling_url(&config.signaling_url);
+        config
+    }
+
+    fn set(&mut self, field: &str, value: &str) {
+        let parsed = match field {
+            "signaling-url" => {
+                self.signaling_url = value.to_string();
+                true
+            }
+            "room" => {
+                self.room = value.to_string();
+                true
+            }
+            "players" => value
+                .parse()
+                .map(|players| self.players = Some(players))
+                .is_ok(),
+            "input-delay" => value.parse().map(|delay| self.input_delay = delay).is_ok(),
+            "adaptive-input-delay" => value
+                .parse()
+                .map(|adaptive| self.adaptive_input_delay = adaptive)
+                .is_ok(),
+            "max-prediction" => value
+                .parse()
+                .map(|prediction| self.max_prediction = prediction)
+                .is_ok(),
+            "matchmaking-timeout" => value
+                .parse()
+                .map(|timeout| self.matchmaking_timeout = timeout)
+                .is_ok(),
+            "disconnect-timeout" => value
+                .parse()
+                .map(|timeout| self.disconnect_timeout = timeout)
+                .is_ok(),
+            _ => false,
+        };
+        if !parsed {
+            warn!("Ignoring the invalid value {:?} for {}", value, field);
+        }
+    }
+
+    /// The format to preselect in the menu, if the configured player count has one
+    pub fn format(&self) -> Option<MatchFormat> {
+        let players = self.players?;
+        let format = MatchFormat::for_player_count(players);
+        if format.is_none() {
+            warn!("There is no match format for {} players", players);
+        }
+        format
+    }
+}
+
+/// Returns the value of `--name value` or `--name=value`, the last one if the flag is given more than
+/// once
+fn flag_value(args: &[String], name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let mut value = None;
+    for (i, arg) in args.iter().enumerate() {
+        if *arg == flag {
+            value = args.get(i + 1).cloned();
+        } else if let Some(inline) = arg.strip_prefix(&format!("{}=", flag)) {
+            value = Some(inline.to_string());
+        }
+    }
+    value
+}
+
+/// Adds the default `ws://` scheme if the url has none, and drops trailing slashes so room names can
+/// be appended
+fn normalize_signaling_url(url: &str) -> String {
+    let url = url.trim().trim_end_matches('/');
+    if url.starts_with("ws://") || url.starts_with("wss://") {
+        url.to_string()
+    } else if url.contains("://") {
+        warn!("The signaling server {} isn't a ws:// or wss:// url", url);
+        url.to_string()
+    } else {
+        format!("ws://{}", url)
+    }
+}
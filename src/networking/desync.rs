@@ -0,0 +1,315 @@
+use crate::game_state::game_mode::OrbState;
+use crate::game_state::safe_zone::SafeZone;
+use crate::game_state::MatchState;
+use crate::map::hazards::LavaPool;
+use crate::map::pickups::PickupSpawner;
+use crate::map::MapRollback;
+use crate::networking::events::PeerHandles;
+use crate::networking::ggrs::GGRSConfig;
+use crate::networking::stats::RollbackFrame;
+use crate::physics::Movement;
+use crate::player::{
+    Health, PlayerBuffs, PlayerId, PlayerMovementState, PlayerMovementStats, RespawnTimer,
+};
+use crate::spell::{SpellCasterId, SpellHits, SpellLifetime};
+use bevy::log::{error, warn};
+use bevy::prelude::{Query, Res, ResMut, Resource, Transform, With};
+use bevy_ggrs::{Rollback, Session};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Frames between two checksums
+pub const CHECKSUM_INTERVAL: u32 = 30;
+/// How many frames of checksums are kept to compare with the ones of the other peers
+const CHECKSUM_HISTORY: u32 = 600;
+/// Where the state of desynced frames is written
+pub const DESYNC_DUMP_DIR: &str = "desyncs";
+
+/// Messages used to find desyncs, sent next to the GGRS messages once the session is started
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DesyncMessage {
+    /// The checksum of the state of a confirmed frame
+    Checksum { frame: u32, checksum: u64 },
+    /// The state of a frame that didn't match, so each peer has both sides of the desync
+    Dump { frame: u32, dump: String },
+}
+
+#[derive(Default, Debug)]
+pub struct DesyncQueues {
+    pub outgoing: Vec<DesyncMessage>,
+    pub incoming: Vec<(String, DesyncMessage)>,
+}
+
+/// Desync messages waiting to be sent or read. GGRS owns the socket once the session is started, so
+/// the socket and the desync systems share the queues instead
+#[derive(Resource, Default, Debug, Clone)]
+pub struct DesyncChannel {
+    pub queues: Arc<Mutex<DesyncQueues>>,
+}
+
+/// The state of a frame as written to a desync dump
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameState {
+    pub checksum: u64,
+    pub dump: String,
+}
+
+/// The checksums of the recent frames, compared with the ones of the other peers once the frames are
+/// confirmed. This GGRS version has no desync detection of its own for P2P sessions
+#[derive(Resource, Default, Debug)]
+pub struct FrameChecksums {
+    pub frames: BTreeMap<u32, FrameState>,
+    /// Checksums received for frames we haven't confirmed yet
+    pending: Vec<(String, u32, u64)>,
+    /// The last frame our checksum was sent for
+    sent_up_to: u32,
+    /// The frames found to be desynced
+    pub desyncs: Vec<u32>,
+}
+
+/// Orders the entities of a checksum the same way on every peer. Rollback ids can't be used for
+/// this, they are handed out in the order entities get spawned locally, which isn't the same on
+/// every peer once spells are respawned by a resimulation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum ChecksumKey {
+    Player {
+        handle: usize,
+    },
+    /// A player casts at most one spell per frame
+    Spell {
+        cast_frame: u32,
+        caster: usize,
+    },
+    /// Map entities never move, so where the level placed them tells them apart
+    Map {
+        x: i32,
+        y: i32,
+    },
+}
+
+/// The rolled back state that is hashed. Components that are also changed outside of the rollback
+/// schedule, like the animation state, would differ between peers without a desync
+type ChecksumQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        Option<&'static PlayerId>,
+        Option<&'static SpellCasterId>,
+        Option<&'static MapRollback>,
+        &'static Transform,
+        Option<&'static Movement>,
+        Option<&'static Health>,
+        Option<&'static PlayerMovementState>,
+        Option<&'static PlayerMovementStats>,
+        Option<&'static SpellLifetime>,
+        Option<&'static LavaPool>,
+        Option<&'static PickupSpawner>,
+        Option<&'static PlayerBuffs>,
+        Option<&'static RespawnTimer>,
+        Option<&'static SpellHits>,
+    ),
+    With<Rollback>,
+>;
+
+/// Runs at the end of the rollback schedule, writes down the state of every few frames. Frames that
+/// are resimulated overwrite their old state, so the state of a confirmed frame is final
+pub fn record_checksum(
+    frame: Res<RollbackFrame>,
+    entities: ChecksumQuery,
+    match_state: Res<MatchState>,
+    safe_zone: Res<SafeZone>,
+    orb_state: Res<OrbState>,
+    mut checksums: ResMut<FrameChecksums>,
+) {
+    if frame.frame % CHECKSUM_INTERVAL != 0 {
+        return;
+    }
+
+    // the Debug output of the values is both the dump and what is hashed, floats are printed so
+    // they read back to the same bits
+    let mut dump = String::new();
+    let _ = writeln!(dump, "frame {}", frame.frame);
+    let _ = writeln!(dump, "{:?}", *match_state);
+    let _ = writeln!(dump, "{:?}", *safe_zone);
+    let _ = writeln!(dump, "{:?}", *orb_state);
+
+    let mut lines = Vec::new();
+    for (
+        player,
+        caster,
+        map,
+        transform,
+        movement,
+        health,
+        movement_state,
+        stats,
+        lifetime,
+        lava,
+        pickup,
+        buffs,
+        respawn,
+        hits,
+    ) in entities.iter()
+    {
+        let key = match (player, caster.zip(lifetime), map) {
+            (Some(player), ..) => ChecksumKey::Player {
+                handle: player.handle,
+            },
+            // spent spells don't take part in the simulation anymore, and peers despawn them once
+            // they confirmed the frame they were spent on, which isn't the same frame for everyone
+            (None, Some((_, lifetime)), _) if lifetime.spent_frame != 0 => continue,
+            (None, Some((caster, lifetime)), _) => ChecksumKey::Spell {
+                cast_frame: lifetime.cast_frame,
+                caster: caster.id.handle,
+            },
+            (None, None, Some(_)) => ChecksumKey::Map {
+                x: transform.translation.x.round() as i32,
+                y: transform.translation.y.round() as i32,
+            },
+            _ => continue,
+        };
+
+        let mut line = format!(
+            "{:?}: {:?} {:?}",
+            key, transform.translation, transform.rotation
+        );
+        let components: [Option<&dyn std::fmt::Debug>; 10] = [
+            movement.map(|x| x as _),
+            health.map(|x| x as _),
+            movement_state.map(|x| x as _),
+            stats.map(|x| x as _),
+            lifetime.map(|x| x as _),
+            lava.map(|x| x as _),
+            pickup.map(|x| x as _),
+            buffs.map(|x| x as _),
+            respawn.map(|x| x as _),
+            hits.map(|x| x as _),
+        ];
+        for component in components.into_iter().flatten() {
+            let _ = write!(line, " {:?}", component);
+        }
+        lines.push((key, line));
+    }
+
+    // map entities can share a position, those are ordered by their state
+    lines.sort();
+    for (_, line) in lines {
+        dump.push_str(&line);
+        dump.push('\n');
+    }
+
+    let checksum = fnv1a(dump.as_bytes());
+    checksums
+        .frames
+        .insert(frame.frame, FrameState { checksum, dump });
+
+    let oldest = frame.frame.saturating_sub(CHECKSUM_HISTORY);
+    checksums.frames = checksums.frames.split_off(&oldest);
+}
+
+/// Sends the checksums of the frames that got confirmed, and compares them with the ones of the
+/// other peers. On a mismatch the state of the frame is written to [`DESYNC_DUMP_DIR`] and sent to
+/// the other peer, which writes it next to its own
+pub fn exchange_checksums(
+    session: Option<Res<Session<GGRSConfig>>>,
+    channel: Option<Res<DesyncChannel>>,
+    peers: Option<Res<PeerHandles>>,
+    mut checksums: ResMut<FrameChecksums>,
+) {
+    let (session, channel) = match (session.as_deref(), channel) {
+        (Some(Session::P2PSession(session)), Some(channel)) => (session, channel),
+        // spectators take no checksums, the messages they receive would pile up for the whole match
+        (_, Some(channel)) => {
+            if let Ok(mut queues) = channel.queues.lock() {
+                queues.incoming.clear();
+                queues.outgoing.clear();
+            }
+            return;
+        }
+        _ => return,
+    };
+    let confirmed = match u32::try_from(session.confirmed_frame()) {
+        Ok(confirmed) => confirmed,
+        Err(_) => return,
+    };
+    let mut queues = match channel.queues.lock() {
+        Ok(queues) => queues,
+        Err(_) => return,
+    };
+
+    if confirmed > checksums.sent_up_to {
+        for (frame, state) in checksums.frames.range(checksums.sent_up_to + 1..=confirmed) {
+            queues.outgoing.push(DesyncMessage::Checksum {
+                frame: *frame,
+                checksum: state.checksum,
+            });
+        }
+        checksums.sent_up_to = confirmed;
+    }
+
+    for (peer, message) in queues.incoming.drain(..) {
+        match message {
+            DesyncMessage::Checksum { frame, checksum } => {
+                checksums.pending.push((peer, frame, checksum))
+            }
+            DesyncMessage::Dump { frame, dump } => {
+                write_dump(frame, &peer_name(&peers, &peer), &dump);
+            }
+        }
+    }
+
+    let pending = std::mem::take(&mut checksums.pending);
+    for (peer, frame, checksum) in pending {
+        if frame > confirmed {
+            checksums.pending.push((peer, frame, checksum));
+            continue;
+        }
+        // too old to compare, or not a frame we took a checksum of
+        let local = match checksums.frames.get(&frame) {
+            Some(local) => local.clone(),
+            None => continue,
+        };
+        if local.checksum != checksum {
+            error!(
+                "desync with {} at frame {}, writing the state to {}",
+                peer, frame, DESYNC_DUMP_DIR
+            );
+            write_dump(frame, "local", &local.dump);
+            queues.outgoing.push(DesyncMessage::Dump {
+                frame,
+                dump: local.dump,
+            });
+            if !checksums.desyncs.contains(&frame) {
+                checksums.desyncs.push(frame);
+            }
+        }
+    }
+}
+
+fn peer_name(peers: &Option<Res<PeerHandles>>, peer: &str) -> String {
+    match peers.as_ref().and_then(|peers| peers.handles.get(peer)) {
+        Some(handle) => format!("player{}", handle + 1),
+        None => peer.to_string(),
+    }
+}
+
+fn write_dump(frame: u32, name: &str, dump: &str) {
+    let path = Path::new(DESYNC_DUMP_DIR).join(format!("frame{}_{}.txt", frame, name));
+    if let Err(error) = fs::create_dir_all(DESYNC_DUMP_DIR).and_then(|_| fs::write(&path, dump)) {
+        warn!("Couldn't write the desync dump {:?}: {}", path, error);
+    }
+}
+
+/// A hash that is the same on every platform and build, unlike the std hashers
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
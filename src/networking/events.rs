@@ -0,0 +1,101 @@
+use crate::networking::ggrs::GGRSConfig;
+use bevy::prelude::{info, warn, Res, ResMut, Resource, Time};
+use bevy::utils::HashMap;
+use bevy_ggrs::ggrs::{GGRSEvent, PlayerHandle};
+use bevy_ggrs::Session;
+
+/// The handle of every remote peer, by peer id. GGRS events only say which peer they are about
+#[derive(Resource, Default, Debug, Clone, PartialEq)]
+pub struct PeerHandles {
+    pub handles: HashMap<String, PlayerHandle>,
+}
+
+/// A peer we haven't heard from for a while
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InterruptedPeer {
+    pub handle: PlayerHandle,
+    /// Seconds until GGRS gives up on the peer and disconnects it
+    pub disconnects_in: f32,
+}
+
+/// The state of the connection to the other peers, as told by the GGRS events. Disconnects themselves
+/// are handled in the rollback schedule, see [`crate::game_state::MatchState::disconnected`]
+#[derive(Resource, Default, Debug, Clone, PartialEq)]
+pub struct ConnectionStatus {
+    pub interrupted: Vec<InterruptedPeer>,
+    pub disconnected: Vec<PlayerHandle>,
+    /// How many times GGRS told us we are ahead of the other peers
+    pub wait_recommendations: u32,
+    /// How many frames we are ahead of the other peers. bevy_ggrs runs the rollback schedule a bit
+    /// slower while this is above zero, which is how the recommended frames are waited for
+    pub frames_ahead: i32,
+}
+
+/// Reads the events of the GGRS session
+pub fn handle_session_events(
+    session: Option<ResMut<Session<GGRSConfig>>>,
+    peers: Option<Res<PeerHandles>>,
+    mut status: ResMut<ConnectionStatus>,
+    time: Res<Time>,
+) {
+    let mut session = match session {
+        Some(session) => session,
+        None => return,
+    };
+    let session = match &mut *session {
+        Session::P2PSession(session) => session,
+        _ => return,
+    };
+    let handle_of = |addr: &String| {
+        peers
+            .as_ref()
+            .and_then(|peers| peers.handles.get(addr).copied())
+    };
+
+    for interrupted in status.interrupted.iter_mut() {
+        interrupted.disconnects_in = (interrupted.disconnects_in - time.delta_seconds()).max(0.0);
+    }
+
+    status.frames_ahead = session.frames_ahead();
+
+    for event in session.events() {
+        match event {
+            GGRSEvent::NetworkInterrupted {
+                addr,
+                disconnect_timeout,
+            } => {
+                warn!("lost the connection to {}", addr);
+                if let Some(handle) = handle_of(&addr) {
+                    status.interrupted.retain(|peer| peer.handle != handle);
+                    status.interrupted.push(InterruptedPeer {
+                        handle,
+                        disconnects_in: disconnect_timeout as f32 / 1000.0,
+                    });
+                }
+            }
+            GGRSEvent::NetworkResumed { addr } => {
+                info!("the connection to {} is back", addr);
+                if let Some(handle) = handle_of(&addr) {
+                    status.interrupted.retain(|peer| peer.handle != handle);
+                }
+            }
+            GGRSEvent::Disconnected { addr } => {
+                warn!("{} disconnected", addr);
+                if let Some(handle) = handle_of(&addr) {
+                    status.interrupted.retain(|peer| peer.handle != handle);
+                    if !status.disconnected.contains(&handle) {
+                        status.disconnected.push(handle);
+                    }
+                }
+            }
+            GGRSEvent::WaitRecommendation { skip_frames } => {
+                info!(
+                    "ahead of the other peers, slowing down for {} frames",
+                    skip_frames
+                );
+                status.wait_recommendations += 1;
+            }
+            _ => {}
+        }
+    }
+}
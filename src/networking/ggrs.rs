@@ -1,4 +1,6 @@
-﻿use bevy_ggrs::ggrs::{Config};
+﻿use bevy_ggrs::ggrs::{Config, InputStatus};
+use bevy_ggrs::{PlayerInputs, Session};
+use bevy::prelude::{Deref, Res, ResMut, Resource};
 use crate::player::input::PlayerControls;
 
 
@@ -8,4 +10,31 @@ impl Config for GGRSConfig {
     type Input = PlayerControls;
     type State = u8;
     type Address = String;
-}
\ No newline at end of file
+}
+
+/// The inputs of every player for the frame being simulated, in handle order. The rollback systems
+/// read these instead of the GGRS inputs, so the replay player can run them without a session
+#[derive(Resource, Default, Clone, Deref)]
+pub struct FrameInputs(pub Vec<(PlayerControls, InputStatus)>);
+
+/// The latest rollback frame that can't be rolled back anymore. Only P2P sessions predict frames,
+/// spectators, replays and headless matches only simulate confirmed ones
+pub fn confirmed_frame(session: Option<&Session<GGRSConfig>>, frame: u32) -> u32 {
+    match session {
+        // the GGRS frames count from 0, the rollback frames from 1
+        Some(Session::P2PSession(session)) => (session.confirmed_frame() + 1).max(0) as u32,
+        _ => frame,
+    }
+}
+
+/// Runs first in the rollback schedule, copies the inputs GGRS is simulating the frame with
+pub fn read_frame_inputs(
+    session: Option<Res<Session<GGRSConfig>>>,
+    ggrs_inputs: Option<Res<PlayerInputs<GGRSConfig>>>,
+    mut inputs: ResMut<FrameInputs>,
+) {
+    // the GGRS inputs of the last session stay around after it ends
+    if let (Some(_), Some(ggrs_inputs)) = (session, ggrs_inputs) {
+        inputs.0 = ggrs_inputs.to_vec();
+    }
+}
@@ -0,0 +1,81 @@
+use crate::networking::RoomNetworkSettings;
+use crate::FPS;
+use bevy::prelude::info;
+use bevy::utils::HashMap;
+use std::collections::VecDeque;
+
+/// The most frames of input delay the adaptive input delay picks
+pub const MAX_ADAPTIVE_INPUT_DELAY: usize = 8;
+/// Seconds between two pings to every peer in the lobby
+pub const PING_INTERVAL: f32 = 0.25;
+/// How many round trip times are kept for each peer
+const ROUND_TRIP_SAMPLES: usize = 20;
+
+/// The round trip times to the peers in the lobby, measured with pings over the lobby channel. GGRS
+/// can't change the input delay of a running session, so the adaptive input delay is picked from
+/// these when the session is built
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct RoundTrips {
+    /// The latest round trip times to each peer in milliseconds, by peer id
+    samples: HashMap<String, VecDeque<u32>>,
+    /// Seconds since the peers were last pinged
+    pub since_ping: f32,
+}
+
+impl RoundTrips {
+    pub fn record(&mut self, peer: String, round_trip: u32) {
+        let samples = self.samples.entry(peer).or_default();
+        samples.push_back(round_trip);
+        if samples.len() > ROUND_TRIP_SAMPLES {
+            samples.pop_front();
+        }
+    }
+
+    /// Forgets the peers that left the room
+    pub fn retain(&mut self, connected: &[String]) {
+        self.samples.retain(|peer, _| connected.contains(peer));
+    }
+
+    /// The round trip time to the slowest peer, none until a peer answered a ping
+    pub fn slowest(&self) -> Option<u32> {
+        self.samples
+            .values()
+            .filter_map(|samples| {
+                // the median ignores the odd slow packet
+                let mut samples = samples.iter().copied().collect::<Vec<_>>();
+                samples.sort_unstable();
+                samples.get(samples.len() / 2).copied()
+            })
+            .max()
+    }
+}
+
+/// The input delay, in frames, that hides most of the given round trip time
+pub fn delay_for_round_trip(round_trip: u32) -> usize {
+    let frame_time = 1000 / FPS as u32;
+    // the inputs only have to travel one way
+    let one_way = round_trip / 2;
+    ((one_way + frame_time - 1) / frame_time) as usize
+}
+
+/// The input delay to start the GGRS session with. The configured input delay is the least the
+/// adaptive input delay picks
+pub fn pick_input_delay(settings: &RoomNetworkSettings, round_trips: &RoundTrips) -> usize {
+    if !settings.adaptive_input_delay {
+        return settings.input_delay;
+    }
+    let round_trip = match round_trips.slowest() {
+        Some(round_trip) => round_trip,
+        None => return settings.input_delay,
+    };
+
+    let delay = delay_for_round_trip(round_trip).clamp(
+        settings.input_delay,
+        MAX_ADAPTIVE_INPUT_DELAY.max(settings.input_delay),
+    );
+    info!(
+        "round trip time is {}ms, using an input delay of {} frames",
+        round_trip, delay
+    );
+    delay
+}
@@ -0,0 +1,162 @@
+use crate::game_state::game_mode::GameMode;
+use crate::game_state::{FriendlyFire, MatchFormat, MatchRoster, RosterChoice};
+use crate::networking::input_delay::RoundTrips;
+use crate::player::{Loadout, MageColor};
+use bevy::prelude::Resource;
+use bevy::utils::HashMap;
+use bevy_ggrs::ggrs::PlayerType;
+use serde::{Deserialize, Serialize};
+
+/// Everything a player picked before the match. Peers send theirs to each other every frame until the
+/// match starts, as the data channel is unreliable and we can't know if the other peers got it
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct LobbyPlayer {
+    /// The index of the level the player voted for
+    pub level: usize,
+    pub mode: GameMode,
+    pub friendly_fire: FriendlyFire,
+    /// The team the player would like to be on, none if they don't mind
+    pub team: Option<usize>,
+    pub loadout: Loadout,
+    pub color: MageColor,
+    pub ready: bool,
+    /// Spectators watch the match instead of playing, the rest of their choices are ignored
+    pub spectator: bool,
+}
+
+impl LobbyPlayer {
+    pub fn roster_choice(&self) -> RosterChoice {
+        RosterChoice {
+            team: self.team,
+            loadout: self.loadout,
+            color: self.color,
+        }
+    }
+}
+
+/// The lobby choices of the local player. Once ready the choices are locked, so every peer builds the
+/// match from the same choices
+#[derive(Resource, Default, Debug, Copy, Clone, PartialEq)]
+pub struct LocalLobbyChoices {
+    pub team: Option<usize>,
+    pub loadout: Loadout,
+    pub color: MageColor,
+    pub ready: bool,
+    /// Set when joining a room to watch the match
+    pub spectator: bool,
+}
+
+/// A player in the lobby, in handle order
+#[derive(Debug, Clone, PartialEq)]
+pub struct LobbySlot {
+    pub local: bool,
+    /// The peer id of a remote player
+    pub peer: Option<String>,
+    /// None until we've heard from the peer
+    pub player: Option<LobbyPlayer>,
+}
+
+/// How far along matchmaking is, shown on the lobby screen
+#[derive(Default, Debug, Clone, PartialEq)]
+pub enum MatchmakingStatus {
+    /// Matchbox doesn't tell us when the signaling server let us in, so until another player shows
+    /// up connecting can't be told apart from waiting in an empty room
+    #[default]
+    Connecting,
+    /// Some players are here, waiting for the room to fill up
+    WaitingForPlayers { missing: usize },
+    /// The room is full, waiting for the lobby state of every player
+    Negotiating,
+    /// Everyone is here, waiting for every player to be ready
+    WaitingForReady,
+    /// More players than the match is for joined the room, someone has to leave
+    TooManyPlayers,
+    /// Matchmaking gave up, with a reason the player can read
+    Failed(String),
+}
+
+/// What we know about the players in the room
+#[derive(Resource, Default, Debug, Clone, PartialEq)]
+pub struct LobbyState {
+    /// The last lobby state received from each peer, by peer id
+    pub peers: HashMap<String, LobbyPlayer>,
+    /// Every connected player in handle order, including the local one. Spectators aren't players
+    pub slots: Vec<LobbySlot>,
+    /// The peer ids of the connected spectators, not counting the local one
+    pub spectators: Vec<String>,
+    pub status: MatchmakingStatus,
+    /// Seconds spent without finding any other player
+    pub elapsed: f32,
+    pub round_trips: RoundTrips,
+}
+
+impl LobbyState {
+    /// Puts the connected players in handle order, the same order the GGRS session is built with.
+    /// Spectators are set apart, and peers we haven't heard from yet are assumed to be players
+    pub fn update_slots(&mut self, players: &[PlayerType<String>], local: LobbyPlayer) {
+        self.slots.clear();
+        self.spectators.clear();
+        for player in players {
+            match player {
+                PlayerType::Remote(peer) => {
+                    let player = self.peers.get(peer).copied();
+                    if player.map_or(false, |player| player.spectator) {
+                        self.spectators.push(peer.clone());
+                    } else {
+                        self.slots.push(LobbySlot {
+                            local: false,
+                            peer: Some(peer.clone()),
+                            player,
+                        });
+                    }
+                }
+                _ if local.spectator => {}
+                _ => self.slots.push(LobbySlot {
+                    local: true,
+                    peer: None,
+                    player: Some(local),
+                }),
+            }
+        }
+    }
+
+    /// Works out the status from the connected players
+    pub fn update_status(&mut self, player_count: usize) {
+        self.status = if self.slots.len() <= 1 {
+            MatchmakingStatus::Connecting
+        } else if self.slots.len() < player_count {
+            MatchmakingStatus::WaitingForPlayers {
+                missing: player_count - self.slots.len(),
+            }
+        } else if self.slots.len() > player_count {
+            MatchmakingStatus::TooManyPlayers
+        } else if self.slots.iter().any(|slot| slot.player.is_none()) {
+            MatchmakingStatus::Negotiating
+        } else {
+            MatchmakingStatus::WaitingForReady
+        };
+        if self.status != MatchmakingStatus::Connecting {
+            self.elapsed = 0.0;
+        }
+    }
+
+    /// Returns the lobby state of every player once the room is full and everyone is ready
+    pub fn ready_players(&self, player_count: usize) -> Option<Vec<LobbyPlayer>> {
+        if self.slots.len() != player_count {
+            return None;
+        }
+        self.slots
+            .iter()
+            .map(|slot| slot.player.filter(|player| player.ready))
+            .collect()
+    }
+}
+
+/// Builds the roster of the match from the lobby state of every player, in handle order
+pub fn build_roster(format: MatchFormat, players: &[LobbyPlayer]) -> MatchRoster {
+    let choices = players
+        .iter()
+        .map(LobbyPlayer::roster_choice)
+        .collect::<Vec<_>>();
+    MatchRoster::new(format, &choices)
+}
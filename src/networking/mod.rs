@@ -1,29 +1,74 @@
-﻿use crate::networking::ggrs::GGRSConfig;
+﻿use crate::game_state::game_mode::{resolve_vote, GameMode, ModeVote};
+use crate::game_state::{FriendlyFire, MatchFormat, MatchSettings, MatchState};
+use crate::map::{resolve_map_vote, MapRegistry, MapRotation, MapVote};
+use crate::networking::config::NetworkConfig;
+use crate::networking::desync::{exchange_checksums, DesyncChannel, FrameChecksums};
+use crate::networking::events::{handle_session_events, ConnectionStatus, PeerHandles};
+use crate::networking::ggrs::{FrameInputs, GGRSConfig};
+use crate::networking::input_delay::{pick_input_delay, PING_INTERVAL};
+use crate::networking::lobby::{
+    build_roster, LobbyPlayer, LobbyState, LocalLobbyChoices, MatchmakingStatus,
+};
+use crate::networking::socket::{LobbyMessage, MatchSocket};
+use crate::networking::stats::{
+    toggle_network_stats, update_network_stats, NetworkStatsOverlay, RollbackFrame,
+    SimulatedFrames,
+};
+use crate::replay::ReplayRecorder;
 use crate::{spawn_players, GameState, FPS};
-use bevy::prelude::{info, App, Commands, Plugin, Res, ResMut, Resource};
-use bevy::tasks::IoTaskPool;
-use bevy_ggrs::ggrs::SessionBuilder;
+use bevy::prelude::{info, warn, App, Commands, Plugin, Res, ResMut, Resource, Time};
+use bevy::tasks::futures_lite::{future, FutureExt};
+use bevy::tasks::{IoTaskPool, Task};
+use bevy_ggrs::ggrs::{PlayerType, SessionBuilder};
 use bevy_ggrs::Session;
+use bevy_ecs_ldtk::LevelSelection;
 use iyes_loopless::prelude::{AppLooplessStateExt, IntoConditionalSystem, NextState};
 use matchbox_socket::WebRtcSocket;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
 
+pub mod config;
+pub mod desync;
+pub mod events;
 pub mod ggrs;
+pub mod input_delay;
+pub mod lobby;
 pub mod rollback_systems;
+pub mod socket;
+pub mod stats;
 
 pub struct NetworkPlugin;
 
 impl Plugin for NetworkPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(wait_for_players.run_in_state(GameState::WaitingForPlayers))
-            .add_enter_system(GameState::WaitingForPlayers, start_matchbox_socket);
-        // insert basic default matchmaking resource for testing
-        //app.insert_resource(RoomNetworkSettings::default_matchmake_room());
-
-        // insert basic default local matchmaking resource for testing
-        //app.insert_resource(RoomNetworkSettings::testing_local());
+        let config = NetworkConfig::load();
+        info!("network config: {:?}", config);
+        if let Some(format) = config.format() {
+            app.insert_resource(MatchSettings {
+                format,
+                ..Default::default()
+            });
+        }
 
-        //testing online
-        app.insert_resource(RoomNetworkSettings::testing_ip());
+        app.add_system(wait_for_players.run_in_state(GameState::WaitingForPlayers))
+            .add_enter_system(GameState::WaitingForPlayers, start_matchbox_socket)
+            .add_system(toggle_network_stats)
+            .add_system(update_network_stats)
+            .init_resource::<NetworkStatsOverlay>()
+            .init_resource::<RollbackFrame>()
+            .init_resource::<FrameInputs>()
+            .init_resource::<SimulatedFrames>()
+            .add_system(handle_session_events)
+            .add_system(exchange_checksums)
+            .init_resource::<FrameChecksums>()
+            .init_resource::<ConnectionStatus>()
+            .insert_resource(RoomNetworkSettings::matchmake_room(
+                &config,
+                MatchFormat::default(),
+            ))
+            .insert_resource(config);
     }
 }
 
@@ -31,73 +76,101 @@ impl Plugin for NetworkPlugin {
 pub struct RoomNetworkSettings {
     // Network type
     pub network_type: MatchmakeType,
-    pub ip: String,
-    pub port: String,
+    /// The matchbox signaling server, `ws://` or `wss://`
+    pub signaling_url: String,
+    /// Prefixes the rooms on the signaling server
+    pub room: String,
     pub player_count: u32,
+    /// The input delay GGRS is started with, the least input delay when it is adaptive
+    pub input_delay: usize,
+    /// Pick the input delay from the round trip time to the other peers, see
+    /// [`input_delay::RoundTrips`]
+    pub adaptive_input_delay: bool,
+    pub max_prediction: usize,
+    /// Seconds to wait for another player before giving up on the matchmaking server
+    pub matchmaking_timeout: f32,
+    /// Seconds without hearing from a peer before it is disconnected
+    pub disconnect_timeout: f32,
 }
 
-impl RoomNetworkSettings {
-    pub fn default_matchmake_room() -> Self {
-        RoomNetworkSettings {
-            network_type: MatchmakeType::Matchmake,
-            ip: "match.gschup.dev".to_string(),
-            port: "".to_string(),
-            player_count: 2,
-        }
-    }
+/// Milliseconds without hearing from a peer before the connection is shown as interrupted
+const DISCONNECT_NOTIFY_DELAY: u64 = 500;
 
-    pub fn custom_matchmake_room(ip: String, port: String, player_count: u32) -> Self {
+impl RoomNetworkSettings {
+    pub fn matchmake_room(config: &NetworkConfig, format: MatchFormat) -> Self {
         RoomNetworkSettings {
             network_type: MatchmakeType::Matchmake,
-            ip,
-            port,
-            player_count,
+            signaling_url: config.signaling_url.clone(),
+            room: config.room.clone(),
+            player_count: format.player_count() as u32,
+            input_delay: config.input_delay,
+            adaptive_input_delay: config.adaptive_input_delay,
+            max_prediction: config.max_prediction,
+            matchmaking_timeout: config.matchmaking_timeout,
+            disconnect_timeout: config.disconnect_timeout,
         }
     }
 
-    pub fn private_room(room_key: String, player_count: u32) -> Self {
+    pub fn private_room(config: &NetworkConfig, format: MatchFormat, room_key: String) -> Self {
         RoomNetworkSettings {
             network_type: MatchmakeType::PrivateRoom(room_key),
-            ip: "match.gschup.dev".to_string(),
-            port: "".to_string(),
-            player_count,
+            ..RoomNetworkSettings::matchmake_room(config, format)
         }
     }
 
-    pub fn testing_ip() -> Self {
-        RoomNetworkSettings {
-            network_type: MatchmakeType::Matchmake,
-            ip: "172.124.208.194".to_string(),
-            port: "6500".to_string(),
-            player_count: 2,
+    /// The url of the room on the signaling server
+    pub fn room_url(&self, format: MatchFormat) -> String {
+        match &self.network_type {
+            // every format gets its own rooms so players are only matched with players wanting the
+            // same teams
+            MatchmakeType::Matchmake => format!(
+                "{}/{}_{}?next={}",
+                self.signaling_url,
+                self.room,
+                format.key(),
+                self.player_count
+            ),
+            // the room code already says which format the room is for. Private rooms aren't split
+            // into groups of players, so spectators can join them too
+            MatchmakeType::PrivateRoom(room_key) => {
+                format!("{}/{}_{}", self.signaling_url, self.room, room_key)
+            }
         }
     }
+}
 
-    pub fn testing_local() -> Self {
-        RoomNetworkSettings {
-            network_type: MatchmakeType::Matchmake,
-            ip: "127.0.0.1".to_string(),
-            port: "6500".to_string(),
-            player_count: 2,
-        }
-    }
+pub enum MatchmakeType {
+    Matchmake,
+    PrivateRoom(String),
 }
 
-/// Default is a local room
-impl Default for RoomNetworkSettings {
-    fn default() -> Self {
-        RoomNetworkSettings {
-            network_type: MatchmakeType::Matchmake,
-            ip: "127.0.0.1".to_string(),
-            port: "3536".to_string(),
-            player_count: 2,
-        }
+// letters and digits that can't be mistaken for each other when a code is read out loud or retyped
+const ROOM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const ROOM_CODE_LENGTH: usize = 5;
+
+/// Makes up a code for a new private room, eg `2V2-KXQP7`. The code starts with the match format so
+/// the players joining the room don't have to pick the same format as the host
+pub fn generate_room_code(format: MatchFormat) -> String {
+    // the hasher is seeded randomly, which is all the randomness we need here
+    let mut seed = RandomState::new().build_hasher().finish();
+    let mut code = format!("{}-", format.key().to_uppercase());
+    for _ in 0..ROOM_CODE_LENGTH {
+        code.push(ROOM_CODE_ALPHABET[(seed % ROOM_CODE_ALPHABET.len() as u64) as usize] as char);
+        seed /= ROOM_CODE_ALPHABET.len() as u64;
     }
+    code
 }
 
-pub enum MatchmakeType {
-    Matchmake,
-    PrivateRoom(String),
+/// Reads a code typed in by the player, returns the format of the room and the normalized code, or
+/// none if it isn't a valid code
+pub fn parse_room_code(code: &str) -> Option<(MatchFormat, String)> {
+    let code = code.trim().to_uppercase();
+    let (format, key) = code.split_once('-')?;
+    let format = MatchFormat::from_key(format)?;
+    if key.len() != ROOM_CODE_LENGTH || !key.bytes().all(|c| ROOM_CODE_ALPHABET.contains(&c)) {
+        return None;
+    }
+    Some((format, code))
 }
 
 /* not needed currently but keeping in case we need it
@@ -108,81 +181,277 @@ pub enum RoomNetworkType {
 
  */
 
+/// Inserted when the local player is watching the match instead of playing it
+#[derive(Resource, Default, Debug, Copy, Clone, PartialEq)]
+pub struct Spectating;
+
+/// How many spectators are watching the match, shown to the players
+#[derive(Resource, Default, Debug, Copy, Clone, PartialEq)]
+pub struct SpectatorCount {
+    pub count: usize,
+}
+
 #[derive(Resource)]
 pub struct WrtcSocket {
-    socket: Option<WebRtcSocket>,
+    socket: Option<MatchSocket>,
+    /// Talks to the signaling server. Finishes when the connection to it is lost, and dropping it
+    /// disconnects us
+    message_loop: Task<()>,
 }
 
-pub fn start_matchbox_socket(mut commands: Commands, settings: Res<RoomNetworkSettings>) {
-    // local ip
-    let room_url: String = match &settings.network_type {
-        MatchmakeType::Matchmake => {
-            format!(
-                "ws://{}:{}/mg23?next={}",
-                settings.ip,
-                settings.port,
-                settings.player_count.to_string()
-            )
-        }
-        MatchmakeType::PrivateRoom(room_key) => {
-            format!(
-                "ws://{}:{}/{}?next={}",
-                settings.ip,
-                settings.port,
-                room_key,
-                settings.player_count.to_string()
-            )
-        }
-    };
+pub fn start_matchbox_socket(
+    mut commands: Commands,
+    settings: Res<RoomNetworkSettings>,
+    match_settings: Res<MatchSettings>,
+) {
+    let room_url = settings.room_url(match_settings.format);
     //let room_url = "ws://172.124.208.194:6500/network_test?next=2";
     info!("connecting to matchbox server: {:?}", room_url);
 
     let (socket, message_loop) = WebRtcSocket::new(room_url);
 
     // The message loop needs to be awaited, or nothing will happen.
-    // We do this here using bevy's task system.
-    IoTaskPool::get().spawn(message_loop).detach();
+    // We do this here using bevy's task system. It panics when the signaling server can't be reached,
+    // which is caught so wait_for_players can tell the player
+    let message_loop = IoTaskPool::get().spawn(async move {
+        let _ = AssertUnwindSafe(message_loop).catch_unwind().await;
+    });
 
+    let desync_channel = DesyncChannel::default();
+    commands.insert_resource(desync_channel.clone());
     commands.insert_resource(WrtcSocket {
-        socket: Some(socket),
+        socket: Some(MatchSocket::new(socket, desync_channel)),
+        message_loop,
     });
+    commands.init_resource::<LobbyState>();
 }
 
 pub fn wait_for_players(
     mut commands: Commands,
     mut socket: ResMut<WrtcSocket>,
+    mut lobby: ResMut<LobbyState>,
     settings: Res<RoomNetworkSettings>,
+    map_vote: Res<MapVote>,
+    mode_vote: Res<ModeVote>,
+    lobby_choices: Res<LocalLobbyChoices>,
+    map_registry: Res<MapRegistry>,
+    mut match_settings: ResMut<MatchSettings>,
+    time: Res<Time>,
 ) {
-    // If there is no socket we've already started the game
+    // If there is no socket we've already started the game, or given up
     if socket.socket.is_none() {
         return;
     }
+
+    lobby.elapsed += time.delta_seconds();
+    if future::block_on(future::poll_once(&mut socket.message_loop)).is_some() {
+        give_up(
+            &mut socket,
+            &mut lobby,
+            format!(
+                "LOST THE CONNECTION TO THE MATCHMAKING SERVER AT {}",
+                settings.signaling_url
+            ),
+        );
+        return;
+    }
+
     let socket_ref = socket.socket.as_mut().unwrap();
 
     // Check for new connections
-    socket_ref.accept_new_connections();
-    let players = socket_ref.players();
+    socket_ref.socket_mut().accept_new_connections();
+    let players = socket_ref.socket().players();
 
     //info!("{}", socket_ref.players().len());
 
-    if socket_ref.players().len() < settings.player_count as usize {
+    // the lobby state is sent every frame, so peers that join later still get it
+    let local = LobbyPlayer {
+        level: map_vote.level,
+        mode: mode_vote.mode,
+        friendly_fire: mode_vote.friendly_fire,
+        team: lobby_choices.team,
+        loadout: lobby_choices.loadout,
+        color: lobby_choices.color,
+        ready: lobby_choices.ready,
+        spectator: lobby_choices.spectator,
+    };
+    socket_ref.broadcast_lobby_message(LobbyMessage::Lobby(local));
+
+    // the round trip times pick the input delay of the session
+    let now = time.elapsed_seconds_f64();
+    lobby.round_trips.since_ping += time.delta_seconds();
+    if lobby.round_trips.since_ping >= PING_INTERVAL {
+        lobby.round_trips.since_ping = 0.0;
+        for peer in socket_ref.socket().connected_peers() {
+            socket_ref.send_lobby_message(peer, &LobbyMessage::Ping { sent_at: now });
+        }
+    }
+
+    let mut start = None;
+    for (peer, message) in socket_ref.receive_lobby_messages() {
+        match message {
+            LobbyMessage::Lobby(player) => {
+                lobby.peers.insert(peer, player);
+            }
+            LobbyMessage::Ping { sent_at } => {
+                socket_ref.send_lobby_message(peer, &LobbyMessage::Pong { sent_at });
+            }
+            LobbyMessage::Pong { sent_at } => {
+                let round_trip = ((now - sent_at) * 1000.0) as u32;
+                lobby.round_trips.record(peer, round_trip);
+            }
+            LobbyMessage::Start { players } => {
+                start = Some((peer, players));
+            }
+        }
+    }
+
+    // forget the peers that left the room
+    let connected = socket_ref.socket().connected_peers();
+    lobby.peers.retain(|peer, _| connected.contains(peer));
+    lobby.round_trips.retain(&connected);
+    lobby.update_slots(&players, local);
+    lobby.update_status(settings.player_count as usize);
+
+    if lobby.status == MatchmakingStatus::Connecting && lobby.elapsed > settings.matchmaking_timeout
+    {
+        give_up(
+            &mut socket,
+            &mut lobby,
+            format!(
+                "NO PLAYERS FOUND IN {} SECONDS, THE MATCHMAKING SERVER AT {} MAY BE UNREACHABLE",
+                settings.matchmaking_timeout as u32, settings.signaling_url
+            ),
+        );
+        return;
+    }
+
+    // the host starts the match once everyone is ready and the other peers follow, so a player
+    // leaving or joining right then can't start different matches on different peers
+    let host = lobby.slots.first().map(|slot| slot.peer.clone());
+    let lobby_players = match host {
+        Some(None) => match lobby.ready_players(settings.player_count as usize) {
+            Some(lobby_players) => {
+                // once started the socket answers every lobby message with this, see MatchSocket
+                socket_ref.broadcast_lobby_message(LobbyMessage::Start {
+                    players: lobby_players.clone(),
+                });
+                lobby_players
+            }
+            None => return,
+        },
+        Some(Some(host)) => match start {
+            Some((peer, lobby_players))
+                if peer == host && lobby_players.len() == lobby.slots.len() =>
+            {
+                lobby_players
+            }
+            _ => return,
+        },
+        None => return,
+    };
+
+    // agree on the map and the rules before starting
+
+    let level = resolve_map_vote(
+        &lobby_players
+            .iter()
+            .map(|player| player.level)
+            .collect::<Vec<_>>(),
+    );
+    let mode = resolve_vote(
+        &GameMode::ALL,
+        &lobby_players
+            .iter()
+            .map(|player| player.mode)
+            .collect::<Vec<_>>(),
+    );
+    let friendly_fire = resolve_vote(
+        &FriendlyFire::ALL,
+        &lobby_players
+            .iter()
+            .map(|player| player.friendly_fire)
+            .collect::<Vec<_>>(),
+    );
+    info!(
+        "Vote finished, playing {:?} on level {} with friendly fire {:?}",
+        mode, level, friendly_fire
+    );
+
+    match_settings.mode = mode;
+    match_settings.friendly_fire = friendly_fire;
+    commands.insert_resource(build_roster(match_settings.format, &lobby_players));
+    // every match is recorded, spectators record it too
+    commands.insert_resource(ReplayRecorder::new(
+        *match_settings,
+        level,
+        lobby_players
+            .iter()
+            .map(LobbyPlayer::roster_choice)
+            .collect(),
+    ));
+    commands.insert_resource(MapRotation::new(&map_registry, level));
+    commands.insert_resource(LevelSelection::Index(level));
+
+    commands.insert_resource(SpectatorCount {
+        count: lobby.spectators.len() + lobby_choices.spectator as usize,
+    });
+    let socket = socket.socket.take().unwrap();
+
+    // spectators follow the inputs of the first player, who sends them once they are confirmed
+    if lobby_choices.spectator {
+        let host = lobby.slots[0].peer.clone().expect("the host is a remote player");
+        info!("watching the match hosted by {}", host);
+        let session = SessionBuilder::<GGRSConfig>::new()
+            .with_num_players(settings.player_count as usize)
+            .with_fps(FPS)
+            .expect("Invalid FPS")
+            .start_spectator_session(host, socket);
+
+        commands.insert_resource(Session::SpectatorSession(session));
+        commands.insert_resource(Spectating);
+        commands.insert_resource(MatchState::new(&match_settings));
+        commands.insert_resource(NextState(GameState::BetweenRound));
         return;
     }
+
     // create a new ggrs session
     let mut session_builder = SessionBuilder::<GGRSConfig>::new()
         .with_num_players(settings.player_count as usize)
-        .with_max_prediction_window(12) // (optional) set max prediction window
-        .with_input_delay(2) // (optional) set input delay for the local player
+        .with_max_prediction_window(settings.max_prediction) // (optional) set max prediction window
+        // the input delay hides the round trip time measured in the lobby when it is adaptive
+        .with_input_delay(pick_input_delay(&settings, &lobby.round_trips))
+        // a peer we don't hear from for this long has left, and forfeits the match
+        .with_disconnect_timeout(Duration::from_secs_f32(settings.disconnect_timeout))
+        .with_disconnect_notify_delay(Duration::from_millis(DISCONNECT_NOTIFY_DELAY))
         .with_fps(FPS)
         .expect("Invalid FPS");
 
-    for (i, player) in players.into_iter().enumerate() {
+    let mut peer_handles = PeerHandles::default();
+    for (i, slot) in lobby.slots.iter().enumerate() {
+        let player = match &slot.peer {
+            Some(peer) => {
+                peer_handles.handles.insert(peer.clone(), i);
+                PlayerType::Remote(peer.clone())
+            }
+            None => PlayerType::Local,
+        };
         session_builder = session_builder
             .add_player(player, i)
             .expect("failed to add player");
     }
 
-    let socket = socket.socket.take().unwrap();
+    // the host sends the confirmed inputs to the spectators, their handles come after the players
+    if lobby.slots[0].local {
+        for (i, peer) in lobby.spectators.iter().enumerate() {
+            session_builder = session_builder
+                .add_player(
+                    PlayerType::Spectator(peer.clone()),
+                    settings.player_count as usize + i,
+                )
+                .expect("failed to add spectator");
+        }
+    }
 
     // move the socket out of the resource (required because GGRS takes ownership of it)
     // start the GGRS session
@@ -191,5 +460,14 @@ pub fn wait_for_players(
         .expect("failed to start session");
 
     commands.insert_resource(Session::P2PSession(session));
+    commands.insert_resource(peer_handles);
+    commands.insert_resource(MatchState::new(&match_settings));
     commands.insert_resource(NextState(GameState::BetweenRound))
 }
+
+/// Stops matchmaking, dropping the socket disconnects us from the room
+fn give_up(socket: &mut WrtcSocket, lobby: &mut LobbyState, reason: String) {
+    warn!("matchmaking failed: {}", reason);
+    socket.socket = None;
+    lobby.status = MatchmakingStatus::Failed(reason);
+}
@@ -1,25 +1,36 @@
-﻿use crate::networking::ggrs::GGRSConfig;
-use crate::physics::Movement;
+﻿use crate::networking::ggrs::FrameInputs;
+use crate::networking::stats::RollbackFrame;
+use crate::physics::{sweep_movement, Movement};
 use crate::player::input::{CAST_SPELL, DASH};
-use crate::player::{MovementState, PlayerCombatState, PlayerId, PlayerMovementState, PlayerMovementStats, PlayerSpellBuffer, PlayerSpells, SpellAction, SpellCastState, TeamId};
-use crate::spell::{DamageDealer, DamageSpellProjectileBundle, SpellAnimation, SpellCasterId, SpellId, SpellLifetime};
-use bevy::log::info;
+use crate::player::{Health, MovementState, PlayerBuffs, PlayerCombatState, PlayerId, PlayerMovementState, PlayerMovementStats, PlayerSpellBuffer, PlayerSpells, SpellAction, SpellCastState, TeamId};
+use crate::spell::{DamageDealer, DamageSpellProjectileBundle, HealDealer, HealSpellBundle, SpellAnimation, SpellCasterId, SpellHits, SpellId, SpellLifetime, EXPLOSION_SPELL, HEAL_SPELL};
 use bevy::prelude::{
-    default, Commands, Entity, Query, Res, ResMut, Time, Transform, With, Without,
+    default, Commands, Entity, Query, Res, ResMut, Transform, Vec2, With, Without,
 };
 use bevy_aseprite::anim::AsepriteAnimation;
 use bevy_aseprite::AsepriteBundle;
-use bevy_ggrs::{PlayerInputs, Rollback, RollbackIdProvider};
-use bevy_sepax2d::prelude::Sepax;
+use bevy_ggrs::{Rollback, RollbackIdProvider};
+use bevy_sepax2d::prelude::{Movable, Sepax};
 use bevy_sepax2d::Convex;
 use sepax2d::prelude::Circle;
 use sepax2d::sat_overlap;
 use crate::assets::SpellSprites;
-use crate::map::{SpawnPoint, Wall};
+use crate::map::hazards::{standing_crates, Crate};
+use crate::game_state::{MatchSettings, MatchState};
+use crate::map::{BlocksMovement, SlowZone};
+use crate::FRAME_TIME;
 
 pub fn handle_spell_casts(
-    inputs: Res<PlayerInputs<GGRSConfig>>,
-    mut players_query: Query<(&PlayerId, &mut PlayerCombatState, &PlayerSpells)>,
+    inputs: Res<FrameInputs>,
+    frame: Res<RollbackFrame>,
+    match_state: Res<MatchState>,
+    mut players_query: Query<(
+        &PlayerId,
+        &mut PlayerCombatState,
+        &PlayerSpells,
+        &PlayerBuffs,
+        &Health,
+    )>,
     //spell_buffer: ResMut<PlayerSpellBuffer>,
     mut commands: Commands,
     game_spells: Res<SpellSprites>,
@@ -29,97 +40,188 @@ pub fn handle_spell_casts(
     let mut info = players_query.iter_mut().collect::<Vec<_>>();
     info.sort_by_key(|x| x.0);
 
-    for (id, mut combat_state, mut spells) in info {
+    for (id, mut combat_state, mut spells, buffs, health) in info {
         let (input, _) = inputs[id.handle];
 
+        // nobody can cast outside of the fight, and dead players can't cast at all
+        if !match_state.is_fighting() || health.current_health == 0 {
+            continue;
+        }
+
         if input.action_vars & CAST_SPELL != 0 {
-            commands.spawn(DamageSpellProjectileBundle {
-                sepax: Sepax {
-                    convex: Convex::Circle(Circle {
-                        position: (input.mouse_position.x as f32, input.mouse_position.y as f32),
-                        radius: 65.0,
-                    }),
-                },
-                damage: DamageDealer { damage_amount: 30 },
-                spell_id: Default::default(),
-                spell_caster_id: SpellCasterId {
-                    id: PlayerId {
-                        handle: id.handle,
-                    },
+            let spell_caster_id = SpellCasterId {
+                id: PlayerId {
+                    handle: id.handle,
                 },
-                spell_lifetime: SpellLifetime {
-                    max_cast_delay: 1.0,
-                    current_cast_delay: 0.0,
-                    max_cast_frame: 0,
-                    max_explosion_frame: 6,
-                },
-                aseprite_bundle: AsepriteBundle {
-                    transform: Transform {
-                        translation: input.mouse_position.extend(20.0),
-                        ..default()
-                    },
-                    animation: AsepriteAnimation::from("Explosion"),
-                    aseprite: game_spells.explosion_spell.clone(),
+            };
+            let spell_lifetime = SpellLifetime {
+                max_cast_delay: 1.0,
+                current_cast_delay: 0.0,
+                max_cast_frame: 0,
+                max_explosion_frame: 6,
+                cast_frame: frame.frame,
+                spent_frame: 0,
+            };
+            let aseprite_bundle = AsepriteBundle {
+                transform: Transform {
+                    translation: input.mouse_position.extend(20.0),
                     ..default()
                 },
-                animation_state: SpellAnimation::CastDelay,
-                rollback_id: Rollback::new(rip.next_id()),
-            });
+                animation: AsepriteAnimation::from("Explosion"),
+                aseprite: game_spells.explosion_spell.clone(),
+                ..default()
+            };
+
+            match input.cast_spell_type {
+                HEAL_SPELL => {
+                    commands.spawn(HealSpellBundle {
+                        sepax: spell_area(input.mouse_position, 48.0),
+                        heal: HealDealer { heal_amount: 20 },
+                        spell_id: SpellId { id: HEAL_SPELL },
+                        spell_caster_id,
+                        spell_lifetime,
+                        spell_hits: Default::default(),
+                        aseprite_bundle,
+                        animation_state: SpellAnimation::CastDelay,
+                        rollback_id: Rollback::new(rip.next_id()),
+                    });
+                }
+                _ => {
+                    commands.spawn(DamageSpellProjectileBundle {
+                        sepax: spell_area(input.mouse_position, 65.0),
+                        damage: DamageDealer {
+                            damage_amount: (30.0 * buffs.damage_multiplier) as u32,
+                        },
+                        spell_id: SpellId {
+                            id: EXPLOSION_SPELL,
+                        },
+                        spell_caster_id,
+                        spell_lifetime,
+                        spell_hits: Default::default(),
+                        aseprite_bundle,
+                        animation_state: SpellAnimation::CastDelay,
+                        rollback_id: Rollback::new(rip.next_id()),
+                    });
+                }
+            }
 
             combat_state.spell_cast_state = SpellCastState::None;
         }
     }
 }
 
+fn spell_area(position: Vec2, radius: f32) -> Sepax {
+    Sepax {
+        convex: Convex::Circle(Circle {
+            position: (position.x, position.y),
+            radius,
+        }),
+    }
+}
+
+/// Counts down the cast delay of every spell by the fixed frame time. A spell goes off once its cast
+/// delay is over, gets that frame to hit the players caught in it and is spent on the next one.
+/// Spent spells are kept so a rollback can bring them back, they are despawned outside of the
+/// rollback schedule by [`despawn_spent_spells`](crate::spell::despawn_spent_spells)
 pub fn update_spell_lifetimes(
-    mut commands: Commands,
-    mut spell_query: Query<(Entity, &mut SpellLifetime, &SpellId, &mut AsepriteAnimation, &SpellAnimation)>,
-    time: Res<Time>,
+    frame: Res<RollbackFrame>,
+    mut spells: Query<(Entity, &mut SpellLifetime)>,
 ) {
     // collect and sort for determinism
-    let mut info = spell_query.iter_mut().collect::<Vec<_>>();
-    info.sort_by_key(|x| x.2);
-
-    for (entity, mut lifetime, _, mut animation, spell_animation) in info {
-        lifetime.current_cast_delay += time.delta_seconds();
-        if lifetime.current_cast_delay >= lifetime.max_cast_delay{
-            if SpellAnimation::Cast != *spell_animation{
-                *animation = AsepriteAnimation::from("Explosion");
-            }
-            if animation.current_frame() == lifetime.max_cast_frame {
-                commands.entity(entity).despawn();
-            }
+    let mut info = spells.iter_mut().collect::<Vec<_>>();
+    info.sort_by_key(|x| x.0);
+
+    for (_, mut lifetime) in info {
+        if lifetime.spent_frame != 0 {
+            continue;
+        }
+        if lifetime.current_cast_delay >= lifetime.max_cast_delay {
+            lifetime.spent_frame = frame.frame;
+        } else {
+            lifetime.current_cast_delay += FRAME_TIME;
         }
     }
 }
 
+/// Damages and heals the players caught in spells that go off. Each spell affects a player at most
+/// once. Damage to the caster's teammates follows the friendly fire setting and heals only ever affect
+/// the caster's team. In modes that score kills a player brought down to zero health by an enemy is
+/// a point for the enemy's team
 pub fn spell_collision_system(
-    mut commands: Commands,
-    spells: Query<(Entity, &Sepax, &SpellCasterId), (With<SpellId>, Without<PlayerId>)>,
-    mut players: Query<(Entity, &Sepax, &PlayerId, &TeamId, &mut Transform), (With<PlayerId>, Without<SpellId>)>,
-    mut spawn_points: Query<(&mut Transform, &SpawnPoint), Without<PlayerId>>,
-
+    mut match_state: ResMut<MatchState>,
+    match_settings: Res<MatchSettings>,
+    mut spells: Query<
+        (
+            Entity,
+            &Sepax,
+            &SpellCasterId,
+            Option<&DamageDealer>,
+            Option<&HealDealer>,
+            &SpellLifetime,
+            &mut SpellHits,
+        ),
+        (With<SpellId>, Without<PlayerId>),
+    >,
+    mut players: Query<(&Sepax, &PlayerId, &TeamId, &mut Health), Without<SpellId>>,
 ) {
+    if !match_state.is_fighting() {
+        return;
+    }
 
     // collect and sort for determinism
-    let mut info = spawn_points.iter_mut().collect::<Vec<_>>();
+    let mut spells = spells.iter_mut().collect::<Vec<_>>();
+    spells.sort_by_key(|x| x.0);
+
+    let mut info = players.iter_mut().collect::<Vec<_>>();
     info.sort_by_key(|x| x.1);
 
-    let mut spawn_points = vec![];
+    for (_, spell_sepax, caster, damage, heal, lifetime, mut hits) in spells {
+        // spells only do anything on the frame they go off
+        if !lifetime.is_going_off() {
+            continue;
+        }
 
-    for (transform, spawn_point) in info {
-        spawn_points.push((transform.translation, spawn_point.0));
-    }
-    
-    for (spell, spell_sepax, spell_caster_id) in spells.iter() {
-        for (enemy, enemy_sepax, player_id, team_id, mut transform) in players.iter_mut() {
-            if spell_caster_id.id.handle != player_id.handle {
-                info!("overlapped enemy");
-                if sat_overlap(enemy_sepax.shape(), spell_sepax.shape()) {
-                    for spawn_point in spawn_points.clone(){
-                        if spawn_point.1.id == team_id.id{
-                            transform.translation = spawn_point.0;
-                        }
+        let caster_team = info
+            .iter()
+            .find(|(_, id, ..)| **id == caster.id)
+            .map(|(_, _, team_id, _)| **team_id);
+
+        for (player_sepax, player_id, team_id, health) in info.iter_mut() {
+            if hits.players & (1 << player_id.handle) != 0
+                || health.current_health == 0
+                || !sat_overlap(player_sepax.shape(), spell_sepax.shape())
+            {
+                continue;
+            }
+
+            let is_ally = caster_team == Some(**team_id);
+
+            if let Some(heal) = heal {
+                if is_ally {
+                    hits.players |= 1 << player_id.handle;
+                    health.current_health =
+                        (health.current_health + heal.heal_amount).min(health.max_health);
+                }
+            }
+
+            if let Some(damage) = damage {
+                // the caster never hits themselves
+                if caster.id == **player_id {
+                    continue;
+                }
+
+                let damage_amount = if is_ally {
+                    match_settings.friendly_fire.ally_damage(damage.damage_amount)
+                } else {
+                    damage.damage_amount
+                };
+
+                hits.players |= 1 << player_id.handle;
+                health.current_health = health.current_health.saturating_sub(damage_amount);
+
+                if health.current_health == 0 && !is_ally && match_settings.mode.rules().scores_kills() {
+                    if let Some(caster_team) = caster_team {
+                        match_state.add_points(caster_team, 1.0);
                     }
                 }
             }
@@ -128,22 +230,29 @@ pub fn spell_collision_system(
 }
 
 pub fn move_players(
-    inputs: Res<PlayerInputs<GGRSConfig>>,
+    inputs: Res<FrameInputs>,
+    match_state: Res<MatchState>,
     mut players_query: Query<(
         &mut Movement,
         &PlayerId,
         &mut PlayerMovementState,
-        &mut Transform,
+        &Health,
     )>,
-    time: Res<Time>,
 ) {
     // collect and sort for determinism
     let mut info = players_query.iter_mut().collect::<Vec<_>>();
     info.sort_by_key(|x| x.1);
 
-    for (mut movement, player, mut player_movement, mut transform) in info {
+    for (mut movement, player, mut player_movement, health) in info {
         let (input, _) = inputs[player.handle];
 
+        // inputs are frozen outside of the fight, and dead players stay where they fell
+        if !match_state.is_fighting() || health.current_health == 0 {
+            movement.velocity = Vec2::ZERO;
+            player_movement.movement_state = MovementState::Idle;
+            continue;
+        }
+
         let move_delta = input.move_direction;
 
         if input.action_vars & DASH != 0 {
@@ -171,43 +280,58 @@ pub fn move_players(
 }
 
 pub fn velocity_system(
-    mut query: Query<(
-        Entity,
-        &mut Movement,
-        &PlayerMovementStats,
-        &mut PlayerMovementState,
-        &mut Transform,
-    )>,
-    time: Res<Time>,
+    mut query: Query<
+        (
+            Entity,
+            &mut Movement,
+            &PlayerMovementStats,
+            &mut PlayerMovementState,
+            &mut Transform,
+            &mut Sepax,
+        ),
+        With<Movable>,
+    >,
+    walls: Query<(Entity, &Sepax), (With<BlocksMovement>, Without<Movable>)>,
+    crates: Query<(Entity, &Sepax, &Health), (With<Crate>, Without<Movable>)>,
+    slow_zones: Query<(Entity, &Sepax, &SlowZone), Without<Movable>>,
 ) {
     // collect and sort for determinism
     let mut info = query.iter_mut().collect::<Vec<_>>();
     info.sort_by_key(|x| x.0);
 
-    for (_, mut movement, stats, mut state, mut transform) in info {
+    let mut walls = walls.iter().chain(standing_crates(crates.iter())).collect::<Vec<_>>();
+    walls.sort_by_key(|x| x.0);
+    let walls = walls.into_iter().map(|x| x.1).collect::<Vec<_>>();
+
+    let mut slow_zones = slow_zones.iter().collect::<Vec<_>>();
+    slow_zones.sort_by_key(|x| x.0);
+
+    for (_, mut movement, stats, mut state, mut transform, mut sepax) in info {
         let mut movement_speed = stats.speed;
-        match state.movement_state {
+        for (_, slow_zone_sepax, slow_zone) in slow_zones.iter() {
+            if sat_overlap(sepax.shape(), slow_zone_sepax.shape()) {
+                movement_speed = movement_speed * slow_zone.speed_multiplier;
+                break;
+            }
+        }
+        let velocity = match state.movement_state {
             MovementState::Dashing {
                 duration,
                 direction,
             } => {
                 movement_speed = movement_speed * stats.dash_power;
-                transform.translation.x += (direction.x * movement_speed) * time.delta_seconds();
-                transform.translation.y += (direction.y * movement_speed) * time.delta_seconds();
-            }
-            MovementState::Walking => {
-                transform.translation.x +=
-                    (movement.velocity.x * movement_speed) * time.delta_seconds();
-                transform.translation.y +=
-                    (movement.velocity.y * movement_speed) * time.delta_seconds();
+                direction * movement_speed
             }
-            MovementState::Idle => {
-                transform.translation.x +=
-                    (movement.velocity.x * movement_speed) * time.delta_seconds();
-                transform.translation.y +=
-                    (movement.velocity.y * movement_speed) * time.delta_seconds();
-            }
-        }
+            MovementState::Walking => movement.velocity * movement_speed,
+            MovementState::Idle => movement.velocity * movement_speed,
+        };
+
+        sweep_movement(
+            &mut sepax,
+            &mut transform,
+            velocity * FRAME_TIME,
+            &walls,
+        );
     }
 }
 
@@ -219,7 +343,6 @@ pub fn update_dash_info(
         &mut PlayerMovementState,
         &mut Transform,
     )>,
-    time: Res<Time>,
 ) {
     // collect and sort for determinism
     let mut info = query.iter_mut().collect::<Vec<_>>();
@@ -231,7 +354,7 @@ pub fn update_dash_info(
                 mut duration,
                 direction,
             } => {
-                duration += time.delta_seconds();
+                duration += FRAME_TIME;
                 if duration >= stats.dash_duration {
                     state.movement_state = MovementState::Idle;
                 } else {
@@ -243,7 +366,7 @@ pub fn update_dash_info(
             }
             _ => {
                 if stats.dash_cooldown_length < state.dash_cooldown {
-                    state.dash_cooldown += time.delta_seconds();
+                    state.dash_cooldown += FRAME_TIME;
                     state.can_dash = false;
                 } else {
                     state.can_dash = true;
@@ -0,0 +1,141 @@
+use crate::networking::desync::{DesyncChannel, DesyncMessage};
+use crate::networking::lobby::LobbyPlayer;
+use bevy::log::warn;
+use bevy_ggrs::ggrs::{Message, NonBlockingSocket};
+use matchbox_socket::WebRtcSocket;
+use serde::{Deserialize, Serialize};
+
+// The first byte of every packet sent over a MatchSocket says who the packet is for
+const GGRS_PACKET: u8 = 0;
+const LOBBY_PACKET: u8 = 1;
+const DESYNC_PACKET: u8 = 2;
+
+/// Messages peers send each other before the GGRS session is started
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum LobbyMessage {
+    /// The votes and choices of the peer, and whether it is ready to start
+    Lobby(LobbyPlayer),
+    /// Asks the peer to send the time back, to measure the round trip time to it
+    Ping { sent_at: f64 },
+    /// The answer to a ping, with the time the ping was sent at
+    Pong { sent_at: f64 },
+    /// Sent by the host once everyone is ready, with the lobby state of every player in handle
+    /// order. Every peer starts the match from these, so a late change in the lobby can't leave the
+    /// peers playing different matches
+    Start { players: Vec<LobbyPlayer> },
+}
+
+/// Wraps the matchbox socket so that lobby messages and GGRS messages can share the same data channel.
+///
+/// Peers don't all start their GGRS session on the same frame, so a peer that has already started
+/// can still receive lobby messages from a peer that hasn't. Those are kept away from GGRS, and
+/// answered with our own last lobby message so the other peer isn't left waiting on a message that
+/// got dropped.
+///
+/// Once the session is started the socket also carries the desync detection messages, see
+/// [`DesyncChannel`].
+pub struct MatchSocket {
+    socket: WebRtcSocket,
+    last_lobby_message: Option<LobbyMessage>,
+    desync_channel: DesyncChannel,
+}
+
+impl MatchSocket {
+    pub fn new(socket: WebRtcSocket, desync_channel: DesyncChannel) -> Self {
+        MatchSocket {
+            socket,
+            last_lobby_message: None,
+            desync_channel,
+        }
+    }
+
+    pub fn socket(&self) -> &WebRtcSocket {
+        &self.socket
+    }
+
+    pub fn socket_mut(&mut self) -> &mut WebRtcSocket {
+        &mut self.socket
+    }
+
+    /// Sends the message to every connected peer
+    pub fn broadcast_lobby_message(&mut self, message: LobbyMessage) {
+        let packet = encode_packet(LOBBY_PACKET, &message);
+        for peer in self.socket.connected_peers() {
+            self.socket.send(packet.clone(), peer);
+        }
+        self.last_lobby_message = Some(message);
+    }
+
+    /// Sends the message to one peer only
+    pub fn send_lobby_message(&mut self, peer: String, message: &LobbyMessage) {
+        self.socket.send(encode_packet(LOBBY_PACKET, message), peer);
+    }
+
+    /// Returns every lobby message received since the last call. GGRS packets that arrive before the
+    /// session is started are dropped, GGRS resends them anyway.
+    pub fn receive_lobby_messages(&mut self) -> Vec<(String, LobbyMessage)> {
+        let mut messages = vec![];
+        for (peer, packet) in self.socket.receive() {
+            if let Some(message) = decode_packet::<LobbyMessage>(LOBBY_PACKET, &packet) {
+                messages.push((peer, message));
+            }
+        }
+        messages
+    }
+}
+
+impl NonBlockingSocket<String> for MatchSocket {
+    fn send_to(&mut self, msg: &Message, addr: &String) {
+        self.socket
+            .send(encode_packet(GGRS_PACKET, msg), addr.clone());
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(String, Message)> {
+        let mut messages = vec![];
+        for (peer, packet) in self.socket.receive() {
+            if let Some(message) = decode_packet::<Message>(GGRS_PACKET, &packet) {
+                messages.push((peer, message));
+            } else if let Some(message) = decode_packet::<DesyncMessage>(DESYNC_PACKET, &packet) {
+                if let Ok(mut queues) = self.desync_channel.queues.lock() {
+                    queues.incoming.push((peer, message));
+                }
+            } else if packet.first() == Some(&LOBBY_PACKET) {
+                // the peer is still in the lobby and is missing something from us
+                if let Some(message) = &self.last_lobby_message {
+                    self.socket
+                        .send(encode_packet(LOBBY_PACKET, message), peer);
+                }
+            }
+        }
+
+        // GGRS polls the socket every frame, which is when the desync messages go out
+        if let Ok(mut queues) = self.desync_channel.queues.lock() {
+            for message in queues.outgoing.drain(..) {
+                let packet = encode_packet(DESYNC_PACKET, &message);
+                for peer in self.socket.connected_peers() {
+                    self.socket.send(packet.clone(), peer);
+                }
+            }
+        }
+        messages
+    }
+}
+
+fn encode_packet<T: Serialize>(kind: u8, message: &T) -> Box<[u8]> {
+    let mut packet = vec![kind];
+    packet.extend(bincode::serialize(message).expect("Failed to serialize packet"));
+    packet.into_boxed_slice()
+}
+
+fn decode_packet<T: for<'de> Deserialize<'de>>(kind: u8, packet: &[u8]) -> Option<T> {
+    match packet.split_first() {
+        Some((packet_kind, data)) if *packet_kind == kind => match bincode::deserialize(data) {
+            Ok(message) => Some(message),
+            Err(error) => {
+                warn!("Dropping malformed packet: {:?}", error);
+                None
+            }
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,119 @@
+use crate::networking::ggrs::GGRSConfig;
+use bevy::prelude::{Input, KeyCode, Reflect, Res, ResMut, Resource, Time};
+use bevy_ggrs::ggrs::{NetworkStats, PlayerHandle};
+use bevy_ggrs::Session;
+
+/// The key that shows and hides the network stats overlay
+pub const NETWORK_STATS_KEY: KeyCode = KeyCode::F3;
+
+/// The frame the rollback schedule is simulating. Rolled back with everything else, so after a
+/// rollback it goes back to the frame being resimulated
+#[derive(Reflect, Default, Resource, Debug, Copy, Clone, PartialEq)]
+pub struct RollbackFrame {
+    pub frame: u32,
+}
+
+/// Counts the frames the rollback schedule simulates. Not rolled back, so resimulated frames are
+/// counted too
+#[derive(Resource, Default, Debug, Copy, Clone, PartialEq)]
+pub struct SimulatedFrames {
+    pub simulated: u32,
+    /// The furthest frame simulated so far
+    pub latest: u32,
+}
+
+/// Everything shown on the network stats overlay
+#[derive(Resource, Default, Debug)]
+pub struct NetworkStatsOverlay {
+    pub visible: bool,
+    /// The GGRS stats of every remote player, by handle
+    pub peers: Vec<(PlayerHandle, NetworkStats)>,
+    /// Frames resimulated because of rollbacks during the last second
+    pub rollback_frames_per_second: u32,
+    /// Frames simulated on predicted inputs, that may still be rolled back
+    pub predicted_frames: u32,
+    window_elapsed: f32,
+    window_simulated: u32,
+    window_latest: u32,
+}
+
+/// Runs in the rollback schedule, keeps track of the simulated frames
+pub fn count_simulated_frames(
+    mut frame: ResMut<RollbackFrame>,
+    mut simulated: ResMut<SimulatedFrames>,
+) {
+    frame.frame += 1;
+    simulated.simulated += 1;
+    simulated.latest = simulated.latest.max(frame.frame);
+}
+
+pub fn toggle_network_stats(keys: Res<Input<KeyCode>>, mut overlay: ResMut<NetworkStatsOverlay>) {
+    if keys.just_pressed(NETWORK_STATS_KEY) {
+        overlay.visible = !overlay.visible;
+    }
+}
+
+/// Reads the network stats from the GGRS session, and works out the rollbacks over the last second.
+/// Runs while the overlay is hidden too, so it has numbers as soon as it is shown
+pub fn update_network_stats(
+    session: Option<Res<Session<GGRSConfig>>>,
+    simulated: Res<SimulatedFrames>,
+    frame: Res<RollbackFrame>,
+    mut overlay: ResMut<NetworkStatsOverlay>,
+    time: Res<Time>,
+) {
+    let session = match session.as_deref() {
+        Some(Session::P2PSession(session)) => session,
+        _ => return,
+    };
+
+    // the stats aren't there until the peers are synchronized
+    overlay.peers = session
+        .remote_player_handles()
+        .into_iter()
+        .filter_map(|handle| {
+            session
+                .network_stats(handle)
+                .ok()
+                .map(|stats| (handle, stats))
+        })
+        .collect();
+
+    overlay.predicted_frames = predicted_frames(frame.frame, session.confirmed_frame());
+
+    // every frame simulated past the furthest frame is new, the rest are resimulations
+    overlay.window_elapsed += time.delta_seconds();
+    if overlay.window_elapsed >= 1.0 {
+        let simulated_frames = simulated.simulated - overlay.window_simulated;
+        let new_frames = simulated.latest - overlay.window_latest;
+        overlay.rollback_frames_per_second = simulated_frames.saturating_sub(new_frames);
+        overlay.window_elapsed = 0.0;
+        overlay.window_simulated = simulated.simulated;
+        overlay.window_latest = simulated.latest;
+    }
+}
+
+/// The frames simulated past the last confirmed one. GGRS counts its frames from 0 and the rollback
+/// frames count from 1, so the confirmed GGRS frame is the rollback frame after it. GGRS confirms -1
+/// until the first frame is confirmed
+fn predicted_frames(frame: u32, confirmed: i32) -> u32 {
+    let confirmed = (confirmed + 1).max(0) as u32;
+    frame.saturating_sub(confirmed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::predicted_frames;
+
+    #[test]
+    fn nothing_is_predicted_once_every_frame_is_confirmed() {
+        assert_eq!(predicted_frames(10, 9), 0);
+        assert_eq!(predicted_frames(0, -1), 0);
+    }
+
+    #[test]
+    fn frames_past_the_confirmed_one_are_predicted() {
+        assert_eq!(predicted_frames(10, 6), 3);
+        assert_eq!(predicted_frames(2, -1), 2);
+    }
+}
@@ -1,9 +1,9 @@
 ﻿use bevy::math::Vec2;
-use bevy::prelude::{App, Component, CoreStage, Entity, IntoSystemDescriptor, Plugin, Query, Reflect, Transform, Without};
+use bevy::prelude::{App, Component, CoreStage, Entity, GlobalTransform, IntoSystemDescriptor, Plugin, Query, Reflect, Transform, With, Without};
 use bevy_sepax2d::plugin::SepaxSystems;
 use bevy_sepax2d::prelude::{Movable, NoCollision, Sepax};
 use sepax2d::sat_collision;
-use crate::map::Wall;
+use crate::map::{BlocksMovement, MapCollider};
 
 #[derive(Reflect, Component, Debug, Copy, Clone, PartialEq)]
 pub struct Movement {
@@ -22,6 +22,27 @@ impl Default for Movement {
     }
 }
 
+/// Where the collider of a map entity sits relative to its translation. Shapes are positioned by
+/// their minimum corner for an AABB, while map entities are placed on their center.
+#[derive(Component, Default, Debug, Copy, Clone, PartialEq)]
+pub struct ColliderOffset {
+    pub offset: Vec2,
+}
+
+impl ColliderOffset {
+    /// The offset of an AABB centered on the translation of its entity
+    pub fn centered(width: f32, height: f32) -> Self {
+        ColliderOffset {
+            offset: Vec2::new(-width / 2.0, -height / 2.0),
+        }
+    }
+}
+
+/// The furthest a shape is allowed to travel before being checked against walls again. Kept under
+/// half the size of a wall tile so that fast movers like dashes can never skip over a wall between
+/// two checks.
+pub const MAX_STEP_DISTANCE: f32 = 4.0;
+
 pub struct SepaxCustomPlugin;
 
 impl Plugin for SepaxCustomPlugin
@@ -105,19 +126,19 @@ pub fn update_movable_system(mut query: Query<(Entity, &Transform, &Movable, &mu
 
 }
 
-/// Updates the position information contained inside of [`Sepax`](crate::components::Sepax)
-/// components to match the entity's translation in the world. This is necessary because
-/// sepax2d is not a Bevy-centric crate, so it does not use Transforms natively.
-pub fn update_walls_system(mut query: Query<(Entity, &Transform, &Wall, &mut Sepax)>)
+/// Updates the position of the map colliders to match their position in the world. Map colliders
+/// are children of their level so their global translation is used rather than their local one.
+pub fn update_walls_system(mut query: Query<(Entity, &GlobalTransform, Option<&ColliderOffset>, &mut Sepax), With<MapCollider>>)
 {
     // collect and sort for determinism
     let mut info = query.iter_mut().collect::<Vec<_>>();
     info.sort_by_key(|x| x.0);
 
-    for (_, transform, _wall, mut sepax) in info
+    for (_, transform, offset, mut sepax) in info
     {
 
-        let position = (transform.translation.x, transform.translation.y);
+        let translation = transform.translation().truncate() + offset.map(|x| x.offset).unwrap_or_default();
+        let position = (translation.x, translation.y);
 
         let shape = sepax.shape_mut();
         shape.set_position(position);
@@ -131,7 +152,7 @@ pub fn update_walls_system(mut query: Query<(Entity, &Transform, &Wall, &mut Sep
 /// component for use in your app. This points away from the immovable object. For example, if you are 
 /// making a platformer and want to check if the player has landed on something, you would check for
 /// axes with a positive y component. 
-pub fn collision_system(mut movable: Query<(Entity, &mut Movable, &mut Sepax, &mut Transform), Without<NoCollision>>, walls: Query<&Sepax, (Without<Movable>, Without<NoCollision>)>)
+pub fn collision_system(mut movable: Query<(Entity, &mut Movable, &mut Sepax, &mut Transform), Without<NoCollision>>, walls: Query<&Sepax, (With<BlocksMovement>, Without<Movable>, Without<NoCollision>)>)
 {
     // collect and sort for determinism
     let mut info = movable.iter_mut().collect::<Vec<_>>();
@@ -167,3 +188,55 @@ pub fn collision_system(mut movable: Query<(Entity, &mut Movable, &mut Sepax, &m
     }
 
 }
+
+/// Moves the shape and transform by `delta`, splitting the movement into sub steps of at most
+/// [`MAX_STEP_DISTANCE`] and pushing the shape out of any wall after every step. This is what keeps
+/// dashes from tunneling through walls no matter how fast they are.
+///
+/// Walls must be passed in a deterministic order. Returns true if any wall was hit during the move.
+///
+/// Only players are swept for now. Spells don't travel, they go off where they were aimed after
+/// their cast delay, so there is no projectile that could tunnel yet. Moving spells should be moved
+/// with this too once there are some.
+pub fn sweep_movement(sepax: &mut Sepax, transform: &mut Transform, delta: Vec2, walls: &[&Sepax]) -> bool
+{
+
+    let steps = (delta.length() / MAX_STEP_DISTANCE).ceil().max(1.0) as u32;
+    let step = delta / steps as f32;
+
+    let shape = sepax.shape_mut();
+    shape.set_position((transform.translation.x, transform.translation.y));
+
+    let mut hit_wall = false;
+
+    for _ in 0..steps
+    {
+
+        let old_position = shape.position();
+        shape.set_position((old_position.0 + step.x, old_position.1 + step.y));
+
+        for wall in walls.iter()
+        {
+
+            let correction = sat_collision(wall.shape(), shape);
+
+            if correction.0.abs() > f32::EPSILON || correction.1.abs() > f32::EPSILON
+            {
+
+                let position = shape.position();
+                shape.set_position((position.0 + correction.0, position.1 + correction.1));
+                hit_wall = true;
+
+            }
+
+        }
+
+    }
+
+    let position = shape.position();
+    transform.translation.x = position.0;
+    transform.translation.y = position.1;
+
+    hit_wall
+
+}
@@ -1,5 +1,7 @@
 ﻿use bevy::input::mouse::MouseButtonInput;
 use crate::camera::CursorWorldPos;
+use crate::game_state::LocalRematchVote;
+use crate::map::LevelLoaded;
 use crate::player::{LocalPlayer, MovementState, PlayerCombatState, PlayerId, PlayerMovementState, PlayerSpells, SpellCastState};
 use bevy::prelude::{In, Input, KeyCode, MouseButton, Query, Reflect, Res, ResMut, Resource, Vec2};
 use bevy_ggrs::ggrs::PlayerHandle;
@@ -25,8 +27,12 @@ pub const DASH: u32 = 1 << 1;
 pub const SHIELD: u32 = 1 << 2;
 // the player has cast a spell, using the information in their mouse_position
 pub const CAST_SPELL: u32 = 1 << 3;
+// the player wants a rematch once the match is over
+pub const REMATCH: u32 = 1 << 4;
+// the player has the current level loaded, the countdown waits for everyone to have it
+pub const LEVEL_LOADED: u32 = 1 << 5;
 
-#[derive(Pod, Zeroable, Copy, Clone, PartialEq, Reflect, Resource)]
+#[derive(Pod, Zeroable, Default, Copy, Clone, PartialEq, Reflect, Resource)]
 #[repr(C)]
 pub struct PlayerControls {
     // the direction the player is requesting to move in
@@ -50,7 +56,9 @@ pub fn input(
         &mut PlayerCombatState,
         &PlayerSpells,
     )>,
-    mut local_handle: ResMut<LocalPlayer>
+    mut local_handle: ResMut<LocalPlayer>,
+    rematch_vote: Res<LocalRematchVote>,
+    level_loaded: Res<LevelLoaded>,
 ) -> PlayerControls {
     let mut action_vars = 0u32;
     let mut cast_spell = 0u32;
@@ -103,6 +111,12 @@ pub fn input(
                     spell_id: spells.spells[0].clone(),
                 }
             }
+            // not every loadout has a second spell
+            if let (true, Some(spell)) = (keys.pressed(KeyCode::Key2), spells.spells.get(1)) {
+                combat_state.spell_cast_state = SpellCastState::Precast {
+                    spell_id: spell.clone(),
+                }
+            }
             if let SpellCastState::Precast {spell_id} = &combat_state.spell_cast_state{
                 if mouse.pressed(MouseButton::Left)  {
                     action_vars |= CAST_SPELL;
@@ -114,6 +128,13 @@ pub fn input(
 
         }
     }
+    if rematch_vote.requested {
+        action_vars |= REMATCH;
+    }
+    if level_loaded.loaded {
+        action_vars |= LEVEL_LOADED;
+    }
+
     PlayerControls {
         move_direction: direction,
         action_vars,
@@ -1,13 +1,20 @@
 ﻿pub(crate) mod input;
 
+use crate::game_state::RosterPlayer;
 use crate::physics::Movement;
-use crate::spell::{SpellCastInfo, SpellId};
-use bevy::math::Vec2;
-use bevy::prelude::{Bundle, Component, FromReflect, Query, Reflect, Resource, With};
+use crate::spell::{SpellCastInfo, SpellId, SpellType, EXPLOSION_SPELL, HEAL_SPELL};
+use bevy::math::{Vec2, Vec3};
+use bevy::prelude::{
+    default, Bundle, Color, Component, FromReflect, Handle, Image, Query, Reflect, Resource,
+    TextureAtlasSprite, Transform, With,
+};
 use bevy_aseprite::anim::AsepriteAnimation;
-use bevy_aseprite::AsepriteBundle;
+use bevy_aseprite::{Aseprite, AsepriteBundle};
 use bevy_ggrs::Rollback;
 use bevy_sepax2d::prelude::{Movable, Sepax};
+use bevy_sepax2d::Convex;
+use sepax2d::prelude::AABB;
+use serde::{Deserialize, Serialize};
 
 pub fn update_animation_state(
     mut query: Query<
@@ -59,7 +66,10 @@ pub struct PlayerBundle {
     pub player_movement: PlayerMovementStats,
     pub player_movement_state: PlayerMovementState,
     pub health: Health,
+    pub buffs: PlayerBuffs,
+    pub respawn_timer: RespawnTimer,
     pub team_id: TeamId,
+    pub color: MageColor,
     // assorted
     pub sepax: Sepax,
     pub movable: Movable,
@@ -69,6 +79,85 @@ pub struct PlayerBundle {
     pub animation_state: AnimationState,
 }
 
+impl PlayerBundle {
+    /// A player of the match standing on its spawn point. The sprites are passed in, so the
+    /// simulation can be run without loading any assets
+    pub fn new(
+        handle: usize,
+        roster_player: &RosterPlayer,
+        spawn_point: Vec3,
+        rollback_id: Rollback,
+        spell_indicator: Handle<Image>,
+        aseprite: Handle<Aseprite>,
+    ) -> Self {
+        let max_health = roster_player.loadout.max_health();
+
+        PlayerBundle {
+            player_id: PlayerId { handle },
+            rollback_id,
+            player_spells: PlayerSpells {
+                autoattack: SpellCastInfo {
+                    spell_type: SpellType::SelfCast,
+                    cooldown: 0.0,
+                    spell_indicator: spell_indicator.clone(),
+                    spell_id: Default::default(),
+                },
+                spells: roster_player
+                    .loadout
+                    .spells()
+                    .iter()
+                    .map(|id| SpellCastInfo {
+                        spell_type: SpellType::SelfCast,
+                        cooldown: 0.0,
+                        spell_indicator: spell_indicator.clone(),
+                        spell_id: SpellId { id: *id },
+                    })
+                    .collect(),
+            },
+            combat_state: Default::default(),
+            player_movement: PlayerMovementStats {
+                speed: 160.0,
+                dash_power: 3.0,
+                dash_duration: 0.15,
+                dash_cooldown_length: 5.0,
+            },
+            player_movement_state: PlayerMovementState {
+                can_dash: true,
+                dash_cooldown: 0.0,
+                movement_state: MovementState::default(),
+            },
+            health: Health {
+                max_health,
+                current_health: max_health,
+            },
+            buffs: Default::default(),
+            respawn_timer: Default::default(),
+            team_id: roster_player.team_id,
+            color: roster_player.color,
+            sepax: Sepax {
+                convex: Convex::AABB(AABB::new((0.0, 0.0 + (handle as f32 * 20.0)), 5.0, 16.0)),
+            },
+            movable: Movable { axes: vec![] },
+            movement: Default::default(),
+            aseprite_bundle: AsepriteBundle {
+                transform: Transform {
+                    translation: spawn_point,
+                    rotation: Default::default(),
+                    scale: Vec3 {
+                        x: 1.0,
+                        y: 1.0,
+                        z: 1.0,
+                    },
+                },
+                animation: AsepriteAnimation::from("Idle"),
+                aseprite,
+                ..default()
+            },
+            animation_state: AnimationState::Idle,
+        }
+    }
+}
+
 pub struct PlayerState {}
 
 #[derive(Reflect, Default, Component, Debug, PartialEq)]
@@ -177,9 +266,115 @@ pub struct Health {
 
 impl Health {}
 
+/// Temporary effects given to the player by pickups
+#[derive(Reflect, Component, Debug, Copy, Clone, PartialEq)]
+pub struct PlayerBuffs {
+    pub damage_multiplier: f32,
+    pub damage_buff_remaining: f32,
+}
+
+impl Default for PlayerBuffs {
+    fn default() -> Self {
+        PlayerBuffs {
+            damage_multiplier: 1.0,
+            damage_buff_remaining: 0.0,
+        }
+    }
+}
+
+/// Counts down the time until a dead player comes back, only used when players respawn during a round
+#[derive(FromReflect, Reflect, Default, Component, Debug, Copy, Clone, PartialEq)]
+pub struct RespawnTimer {
+    pub waiting: bool,
+    pub remaining: f32,
+}
+
 #[derive(
     FromReflect, Reflect, Default, Eq, PartialEq, Debug, PartialOrd, Ord, Copy, Clone, Component,
 )]
 pub struct TeamId {
     pub id: usize,
 }
+
+/// The set of spells a player picks in the lobby
+#[derive(Serialize, Deserialize, Default, Eq, PartialEq, Hash, Debug, Copy, Clone)]
+pub enum Loadout {
+    /// Only explosions, but can take more hits
+    #[default]
+    Battlemage,
+    /// Explosions and heals for the team
+    Support,
+}
+
+impl Loadout {
+    pub const ALL: [Loadout; 2] = [Loadout::Battlemage, Loadout::Support];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Loadout::Battlemage => "BATTLEMAGE",
+            Loadout::Support => "SUPPORT",
+        }
+    }
+
+    /// The spells on the spell keys, in key order
+    pub fn spells(&self) -> &'static [u32] {
+        match self {
+            Loadout::Battlemage => &[EXPLOSION_SPELL],
+            Loadout::Support => &[EXPLOSION_SPELL, HEAL_SPELL],
+        }
+    }
+
+    pub fn max_health(&self) -> u32 {
+        match self {
+            Loadout::Battlemage => 120,
+            Loadout::Support => 100,
+        }
+    }
+}
+
+/// The color of a player's mage, only visual
+#[derive(
+    Serialize, Deserialize, Default, Eq, PartialEq, Hash, Debug, Copy, Clone, Component,
+)]
+pub enum MageColor {
+    #[default]
+    Red,
+    Blue,
+    Green,
+    Purple,
+}
+
+impl MageColor {
+    pub const ALL: [MageColor; 4] = [
+        MageColor::Red,
+        MageColor::Blue,
+        MageColor::Green,
+        MageColor::Purple,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MageColor::Red => "RED",
+            MageColor::Blue => "BLUE",
+            MageColor::Green => "GREEN",
+            MageColor::Purple => "PURPLE",
+        }
+    }
+
+    /// The tint applied to the mage sprite, which is drawn red
+    pub fn tint(&self) -> Color {
+        match self {
+            MageColor::Red => Color::WHITE,
+            MageColor::Blue => Color::rgb(0.4, 0.6, 1.0),
+            MageColor::Green => Color::rgb(0.5, 1.0, 0.5),
+            MageColor::Purple => Color::rgb(0.9, 0.5, 1.0),
+        }
+    }
+}
+
+/// Tints the mage sprites with the color their player picked
+pub fn update_mage_colors(mut players: Query<(&MageColor, &mut TextureAtlasSprite)>) {
+    for (color, mut sprite) in players.iter_mut() {
+        sprite.color = color.tint();
+    }
+}
@@ -0,0 +1,423 @@
+use crate::assets::MapAssets;
+use crate::game_state::{
+    teardown_match, MatchRoster, MatchSettings, MatchState, RosterChoice, RoundPhase,
+};
+use crate::map::{LevelLoaded, MapRegistry, MapRotation};
+use crate::networking::ggrs::{FrameInputs, GGRSConfig};
+use crate::networking::stats::RollbackFrame;
+use crate::networking::Spectating;
+use crate::player::input::PlayerControls;
+use crate::player::PlayerId;
+use crate::{spawn_map_world, GameState, FPS};
+use bevy::ecs::schedule::Stage;
+use bevy::prelude::{
+    info, warn, App, Commands, Mut, Plugin, Res, ResMut, Resource, Schedule, Time, With, World,
+};
+use bevy_ecs_ldtk::LevelSelection;
+use bevy_ggrs::ggrs::InputStatus;
+use bevy_ggrs::Session;
+use iyes_loopless::prelude::{AppLooplessStateExt, CurrentState, NextState};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::mem::{discriminant, size_of};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Changes whenever the replay files or the simulation change in a way old replays can't be played
+pub const REPLAY_VERSION: u32 = 2;
+/// Where every match is recorded
+pub const REPLAY_DIR: &str = "replays";
+const REPLAY_EXTENSION: &str = "replay";
+/// The playback speeds to pick from
+pub const PLAYBACK_SPEEDS: [f32; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+/// The most frames played in one update, which is also how fast seeking forward goes
+const MAX_FRAMES_PER_UPDATE: u32 = 8;
+
+pub struct ReplayPlugin;
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(confirm_recorded_inputs)
+            .add_enter_system(GameState::Menu, save_replay)
+            .add_enter_system(GameState::Menu, restart_replay.after(teardown_match))
+            .add_enter_system(GameState::Menu, list_replays)
+            .add_system(run_replay)
+            .init_resource::<ReplayBrowser>();
+    }
+}
+
+/// A recorded match. The simulation only depends on the settings, the roster and the inputs, so
+/// playing the inputs back through the rollback schedule plays out the same match. There is no seed,
+/// as nothing in the simulation is random
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Replay {
+    /// The [`REPLAY_VERSION`] the replay was recorded with
+    pub version: u32,
+    /// The version of the game the replay was recorded with
+    pub game_version: String,
+    pub settings: MatchSettings,
+    /// The level the map rotation starts at
+    pub level: usize,
+    /// The lobby choices of every player, in handle order
+    pub players: Vec<RosterChoice>,
+    /// The inputs of every player for every frame, in handle order
+    inputs: Vec<u8>,
+    /// The frame each disconnected player got disconnected at, by handle
+    disconnects: Vec<(usize, u32)>,
+}
+
+impl Replay {
+    /// How many frames were recorded
+    pub fn frame_count(&self) -> u32 {
+        let frame_size = size_of::<PlayerControls>() * self.players.len().max(1);
+        (self.inputs.len() / frame_size) as u32
+    }
+
+    /// The inputs of every player for a frame, counting from 1 like [`RollbackFrame`]
+    pub fn frame_inputs(&self, frame: u32) -> Option<Vec<(PlayerControls, InputStatus)>> {
+        if frame == 0 || frame > self.frame_count() {
+            return None;
+        }
+        let input_size = size_of::<PlayerControls>();
+        let start = (frame - 1) as usize * input_size * self.players.len();
+        let inputs = (0..self.players.len())
+            .map(|handle| {
+                let offset = start + handle * input_size;
+                let controls = bytemuck::pod_read_unaligned::<PlayerControls>(
+                    &self.inputs[offset..offset + input_size],
+                );
+                let disconnected = self
+                    .disconnects
+                    .iter()
+                    .any(|(player, at)| *player == handle && *at <= frame);
+                let status = if disconnected {
+                    InputStatus::Disconnected
+                } else {
+                    InputStatus::Confirmed
+                };
+                (controls, status)
+            })
+            .collect();
+        Some(inputs)
+    }
+
+    /// Writes the replay to [`REPLAY_DIR`], named after the time it was saved at
+    pub fn save(&self) -> Result<PathBuf, String> {
+        let bytes = bincode::serialize(self).map_err(|error| error.to_string())?;
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+        let path = Path::new(REPLAY_DIR).join(format!("match_{}.{}", seconds, REPLAY_EXTENSION));
+        fs::create_dir_all(REPLAY_DIR)
+            .and_then(|_| fs::write(&path, bytes))
+            .map_err(|error| error.to_string())?;
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Replay, String> {
+        let bytes = fs::read(path).map_err(|error| error.to_string())?;
+        let replay = bincode::deserialize::<Replay>(&bytes)
+            .map_err(|_| "NOT A REPLAY, OR RECORDED WITH ANOTHER VERSION".to_string())?;
+        if replay.version != REPLAY_VERSION {
+            return Err(format!(
+                "RECORDED WITH VERSION {} OF THE GAME, WHICH CAN'T BE PLAYED BACK",
+                replay.game_version
+            ));
+        }
+        Ok(replay)
+    }
+}
+
+/// Records the inputs of the match being played. The inputs of a frame can change until GGRS confirms
+/// them, so they are only added to the replay once confirmed
+#[derive(Resource)]
+pub struct ReplayRecorder {
+    replay: Replay,
+    /// The inputs of the frames that may still be resimulated, by frame
+    unconfirmed: BTreeMap<u32, Vec<(PlayerControls, InputStatus)>>,
+}
+
+impl ReplayRecorder {
+    pub fn new(settings: MatchSettings, level: usize, players: Vec<RosterChoice>) -> Self {
+        ReplayRecorder {
+            replay: Replay {
+                version: REPLAY_VERSION,
+                game_version: env!("CARGO_PKG_VERSION").to_string(),
+                settings,
+                level,
+                players,
+                inputs: vec![],
+                disconnects: vec![],
+            },
+            unconfirmed: BTreeMap::new(),
+        }
+    }
+
+    /// The frames recorded and confirmed so far
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    /// Moves the inputs of the frames up to the given one to the replay
+    pub(crate) fn confirm(&mut self, confirmed: u32) {
+        loop {
+            let frame = self.replay.frame_count() + 1;
+            if frame > confirmed {
+                break;
+            }
+            let inputs = match self.unconfirmed.remove(&frame) {
+                Some(inputs) => inputs,
+                None => break,
+            };
+            for (handle, (controls, status)) in inputs.iter().enumerate() {
+                self.replay
+                    .inputs
+                    .extend_from_slice(bytemuck::bytes_of(controls));
+                if matches!(status, InputStatus::Disconnected)
+                    && !self
+                        .replay
+                        .disconnects
+                        .iter()
+                        .any(|(player, _)| *player == handle)
+                {
+                    self.replay.disconnects.push((handle, frame));
+                }
+            }
+        }
+        // forget the frames that are already in the replay
+        self.unconfirmed = self.unconfirmed.split_off(&(self.replay.frame_count() + 1));
+    }
+}
+
+/// Runs in the rollback schedule, writes down the inputs the frame is simulated with
+pub fn record_inputs(
+    frame: Res<RollbackFrame>,
+    inputs: Res<FrameInputs>,
+    recorder: Option<ResMut<ReplayRecorder>>,
+) {
+    if let Some(mut recorder) = recorder {
+        recorder.unconfirmed.insert(frame.frame, inputs.0.clone());
+    }
+}
+
+/// Adds the frames GGRS confirmed to the replay
+fn confirm_recorded_inputs(
+    session: Option<Res<Session<GGRSConfig>>>,
+    frame: Res<RollbackFrame>,
+    recorder: Option<ResMut<ReplayRecorder>>,
+) {
+    let mut recorder = match recorder {
+        Some(recorder) => recorder,
+        None => return,
+    };
+    // the GGRS frames count from 0, the rollback frames from 1
+    let confirmed = match session.as_deref() {
+        Some(Session::P2PSession(session)) => (session.confirmed_frame() + 1).max(0) as u32,
+        // spectators only simulate confirmed frames
+        Some(Session::SpectatorSession(_)) => frame.frame,
+        _ => return,
+    };
+    recorder.confirm(confirmed);
+}
+
+/// Saves the recording of the match that just ended
+fn save_replay(mut commands: Commands, recorder: Option<Res<ReplayRecorder>>) {
+    let recorder = match recorder {
+        Some(recorder) => recorder,
+        None => return,
+    };
+    commands.remove_resource::<ReplayRecorder>();
+    if recorder.replay.frame_count() == 0 {
+        return;
+    }
+    match recorder.replay.save() {
+        Ok(path) => info!("saved the replay of the match to {:?}", path),
+        Err(error) => warn!("couldn't save the replay of the match: {}", error),
+    }
+}
+
+/// The rollback schedule, run by the replay player instead of GGRS
+#[derive(Resource)]
+pub struct ReplaySchedule(pub Schedule);
+
+/// A replay being played back
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    pub replay: Replay,
+    pub paused: bool,
+    /// One of [`PLAYBACK_SPEEDS`]
+    pub speed: f32,
+    /// The frame playback is fast forwarding to
+    pub seek_to: Option<u32>,
+    /// Set to play the replay again from the start, which is how seeking backwards works
+    restart: bool,
+    /// Fractions of a frame left over by the playback speed
+    accumulator: f32,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        ReplayPlayback {
+            replay,
+            paused: false,
+            speed: 1.0,
+            seek_to: None,
+            restart: false,
+            accumulator: 0.0,
+        }
+    }
+
+    /// Goes to the given frame. Returns true when the replay has to be played again from the start to
+    /// get there, by going through the menu
+    pub fn seek(&mut self, frame: u32, current_frame: u32) -> bool {
+        self.seek_to = Some(frame.min(self.replay.frame_count()));
+        self.restart = frame < current_frame;
+        self.restart
+    }
+
+    /// How many frames to play this update
+    fn frames_to_play(&mut self, delta_seconds: f32, current_frame: u32) -> u32 {
+        if let Some(seek_to) = self.seek_to {
+            if current_frame < seek_to {
+                return (seek_to - current_frame).min(MAX_FRAMES_PER_UPDATE);
+            }
+            self.seek_to = None;
+        }
+        if self.paused {
+            return 0;
+        }
+        self.accumulator += delta_seconds * self.speed * FPS as f32;
+        let frames = (self.accumulator as u32).min(MAX_FRAMES_PER_UPDATE);
+        self.accumulator = (self.accumulator - frames as f32).min(1.0);
+        frames
+    }
+}
+
+/// The replays in [`REPLAY_DIR`], newest first. Listed every time the menu is shown
+#[derive(Resource, Default, Debug, Clone, PartialEq)]
+pub struct ReplayBrowser {
+    pub files: Vec<PathBuf>,
+    /// Why the last replay picked couldn't be played
+    pub error: Option<String>,
+}
+
+fn list_replays(mut browser: ResMut<ReplayBrowser>) {
+    let mut files = fs::read_dir(REPLAY_DIR)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.extension()
+                        .map_or(false, |ext| ext == REPLAY_EXTENSION)
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    // the file names start with the time they were saved at
+    files.sort();
+    files.reverse();
+    browser.files = files;
+}
+
+/// Sets the match up as it was recorded, and starts playing it back once the players are spawned
+pub fn start_replay(
+    commands: &mut Commands,
+    playback: ReplayPlayback,
+    map_registry: &MapRegistry,
+    map_assets: &MapAssets,
+) {
+    let replay = &playback.replay;
+    info!(
+        "playing back a replay of {} frames, recorded with version {}",
+        replay.frame_count(),
+        replay.game_version
+    );
+    commands.insert_resource(replay.settings);
+    commands.insert_resource(MatchRoster::new(replay.settings.format, &replay.players));
+    commands.insert_resource(MapRotation::new(map_registry, replay.level));
+    commands.insert_resource(LevelSelection::Index(replay.level));
+    commands.insert_resource(MatchState::new(&replay.settings));
+    commands.insert_resource(Spectating);
+    spawn_map_world(commands, map_assets);
+    commands.insert_resource(playback);
+    commands.insert_resource(NextState(GameState::BetweenRound));
+}
+
+/// Runs after the match is torn down when going back to the menu, starts the replay again if it is
+/// seeking backwards
+fn restart_replay(
+    mut commands: Commands,
+    playback: Option<Res<ReplayPlayback>>,
+    map_registry: Res<MapRegistry>,
+    map_assets: Res<MapAssets>,
+) {
+    let playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    if !playback.restart {
+        commands.remove_resource::<ReplayPlayback>();
+        return;
+    }
+    let restarted = ReplayPlayback {
+        replay: playback.replay.clone(),
+        paused: playback.paused,
+        speed: playback.speed,
+        seek_to: playback.seek_to,
+        restart: false,
+        accumulator: 0.0,
+    };
+    start_replay(&mut commands, restarted, &map_registry, &map_assets);
+}
+
+/// Plays the frames of the replay through the rollback schedule
+fn run_replay(world: &mut World) {
+    if !world.contains_resource::<ReplayPlayback>()
+        || world.resource::<CurrentState<GameState>>().0 == GameState::Menu
+    {
+        return;
+    }
+    // the inputs are recorded from the countdown, which covers the players being spawned
+    let mut players = world.query_filtered::<(), With<PlayerId>>();
+    if players.iter(world).next().is_none() {
+        return;
+    }
+    // the recorded countdown waited for every peer to load the level, playback waits for this one
+    if matches!(
+        world.resource::<MatchState>().phase,
+        RoundPhase::Countdown { .. }
+    ) && !world.resource::<LevelLoaded>().loaded
+    {
+        return;
+    }
+
+    world.resource_scope(|world, mut schedule: Mut<ReplaySchedule>| {
+        world.resource_scope(|world, mut playback: Mut<ReplayPlayback>| {
+            let delta_seconds = world.resource::<Time>().delta_seconds();
+            let current_frame = world.resource::<RollbackFrame>().frame;
+            let frames = playback.frames_to_play(delta_seconds, current_frame);
+
+            for _ in 0..frames {
+                let frame = world.resource::<RollbackFrame>().frame + 1;
+                let inputs = match playback.replay.frame_inputs(frame) {
+                    Some(inputs) => inputs,
+                    None => {
+                        playback.paused = true;
+                        break;
+                    }
+                };
+                world.insert_resource(FrameInputs(inputs));
+
+                let phase = world.resource::<MatchState>().phase;
+                schedule.0.run(world);
+                // stop at the end of a phase so the game state follows it and the next map is loaded
+                // before the countdown is over
+                if discriminant(&phase) != discriminant(&world.resource::<MatchState>().phase) {
+                    break;
+                }
+            }
+        });
+    });
+}
@@ -1,14 +1,17 @@
 ﻿use crate::camera::CursorWorldPos;
 use crate::player::{AnimationState, LocalPlayer, PlayerCombatState, PlayerId, PlayerMovementState, PlayerSpells, SpellCastState};
+use crate::networking::ggrs::{confirmed_frame, GGRSConfig};
+use crate::networking::stats::RollbackFrame;
 use bevy::app::App;
 use bevy::prelude::{
     Bundle, Commands, Component, Entity, FromReflect, Handle, Image, In, Local, Plugin, Query,
-    Reflect, Res, Resource, Transform, Vec2,
+    Reflect, Res, Resource, Transform, Vec2, Visibility,
 };
 use bevy::sprite::SpriteBundle;
 use bevy::utils::{default, HashMap};
+use bevy_aseprite::anim::AsepriteAnimation;
 use bevy_aseprite::AsepriteBundle;
-use bevy_ggrs::Rollback;
+use bevy_ggrs::{Rollback, Session};
 use bevy_sepax2d::components::Sepax;
 use bevy_sepax2d::prelude::Movable;
 
@@ -16,7 +19,8 @@ pub struct SpellPlugin;
 
 impl Plugin for SpellPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(show_spell_preview);
+        app.add_system(show_spell_preview)
+            .add_system(despawn_spent_spells);
     }
 }
 
@@ -72,11 +76,55 @@ pub fn show_spell_preview(
     }
 }
 
+/// Plays the explosion of the spells that went off, and despawns them once it is over. A spell is
+/// only despawned once the frame it was spent on is confirmed: rolling back to before that frame
+/// would bring it back with only its rollback components, so it couldn't hit anything
+pub fn despawn_spent_spells(
+    mut commands: Commands,
+    session: Option<Res<Session<GGRSConfig>>>,
+    frame: Res<RollbackFrame>,
+    mut spells: Query<(
+        Entity,
+        &SpellLifetime,
+        &mut SpellAnimation,
+        &mut AsepriteAnimation,
+        &mut Visibility,
+    )>,
+) {
+    let confirmed = confirmed_frame(session.as_deref(), frame.frame);
+    for (entity, lifetime, mut state, mut animation, mut visibility) in spells.iter_mut() {
+        if lifetime.current_cast_delay < lifetime.max_cast_delay {
+            continue;
+        }
+        match *state {
+            SpellAnimation::Indicator | SpellAnimation::CastDelay => {
+                *animation = AsepriteAnimation::from("Explosion");
+                *state = SpellAnimation::Cast;
+            }
+            SpellAnimation::Cast => {
+                if animation.current_frame() >= lifetime.max_explosion_frame {
+                    *state = SpellAnimation::PostCast;
+                    visibility.is_visible = false;
+                }
+            }
+            SpellAnimation::PostCast => {
+                if lifetime.spent_frame != 0 && lifetime.spent_frame <= confirmed {
+                    commands.entity(entity).despawn();
+                }
+            }
+        }
+    }
+}
+
 // spells have two parts that are not connected perse
 // the cast section of a spell, this controls all the cast timings, etc. This isnt set per a spell technically but basically would always be unique per a spell
 // this controls the spell cooldown, how long it takes to cast, etc
 // the second part is the spell entity and all its components. These control the actual spell qualities, its shapes, how it spawns, etc
 
+/// The spell ids sent in [`PlayerControls::cast_spell_type`](crate::player::input::PlayerControls)
+pub const EXPLOSION_SPELL: u32 = 0;
+pub const HEAL_SPELL: u32 = 1;
+
 /// The resource that holds all the spells in the game
 #[derive(FromReflect, Reflect, Default, PartialEq, Debug, Clone, Resource)]
 pub struct GameSpells {
@@ -90,6 +138,7 @@ pub struct DamageSpellProjectileBundle {
     pub spell_id: SpellId,
     pub spell_caster_id: SpellCasterId,
     pub spell_lifetime: SpellLifetime,
+    pub spell_hits: SpellHits,
     pub aseprite_bundle: AsepriteBundle,
     pub animation_state: SpellAnimation,
     pub rollback_id: Rollback,
@@ -99,6 +148,19 @@ impl DamageSpellProjectileBundle {
     fn spawn_spell() {}
 }
 
+#[derive(Bundle)]
+pub struct HealSpellBundle {
+    pub sepax: Sepax,
+    pub heal: HealDealer,
+    pub spell_id: SpellId,
+    pub spell_caster_id: SpellCasterId,
+    pub spell_lifetime: SpellLifetime,
+    pub spell_hits: SpellHits,
+    pub aseprite_bundle: AsepriteBundle,
+    pub animation_state: SpellAnimation,
+    pub rollback_id: Rollback,
+}
+
 /// The cast type of the spell
 #[derive(FromReflect, Reflect, PartialEq, Debug, Clone)]
 pub enum SpellType {
@@ -133,6 +195,18 @@ pub struct SpellLifetime {
     /// Not used rn but keep for future.
     pub max_cast_frame: usize,
     pub max_explosion_frame: usize,
+    /// The rollback frame the spell was cast on
+    pub cast_frame: u32,
+    /// The rollback frame the spell was spent on, 0 while it hasn't gone off yet. Spent spells are kept
+    /// until that frame is confirmed, see [`despawn_spent_spells`]
+    pub spent_frame: u32,
+}
+
+impl SpellLifetime {
+    /// Whether the spell goes off this frame, the only frame it hits anything on
+    pub fn is_going_off(&self) -> bool {
+        self.spent_frame == 0 && self.current_cast_delay >= self.max_cast_delay
+    }
 }
 
 /// The id of the player who cast the spell
@@ -147,6 +221,19 @@ pub struct DamageDealer {
     pub damage_amount: u32,
 }
 
+/// A struct signifying the spell heals the caster's team. Heals never affect enemies
+#[derive(FromReflect, Reflect, Eq, PartialEq, Debug, PartialOrd, Ord, Clone, Component)]
+pub struct HealDealer {
+    pub heal_amount: u32,
+}
+
+/// The players a spell has already damaged or healed, as a bitmask of player handles, so each spell only hits
+/// a player once
+#[derive(FromReflect, Reflect, Default, Eq, PartialEq, Debug, Copy, Clone, Component)]
+pub struct SpellHits {
+    pub players: u32,
+}
+
 #[derive(FromReflect, Reflect, Eq, PartialEq, Debug, PartialOrd, Ord, Clone, Component)]
 pub enum SpellAnimation {
     Indicator,
@@ -1,12 +1,28 @@
-﻿use crate::assets::MenuSprites;
-use crate::networking::RoomNetworkSettings;
-use crate::GameState;
+﻿use crate::assets::{MapAssets, MenuSprites};
+use crate::camera::{SpectatorCamera, SpectatorCameraMode};
+use crate::game_state::game_mode::{GameMode, ModeVote};
+use crate::game_state::{FriendlyFire, LocalRematchVote, MatchFormat, MatchSettings, MatchState, RoundPhase};
+use crate::map::{MapRegistry, MapVote};
+use crate::networking::lobby::{LobbyState, LocalLobbyChoices, MatchmakingStatus};
+use crate::networking::config::NetworkConfig;
+use crate::networking::desync::FrameChecksums;
+use crate::networking::events::ConnectionStatus;
+use crate::networking::input_delay::MAX_ADAPTIVE_INPUT_DELAY;
+use crate::networking::stats::{NetworkStatsOverlay, RollbackFrame, NETWORK_STATS_KEY};
+use crate::networking::{
+    generate_room_code, parse_room_code, MatchmakeType, RoomNetworkSettings, Spectating,
+    SpectatorCount,
+};
+use crate::player::{Loadout, MageColor};
+use crate::replay::{start_replay, Replay, ReplayBrowser, ReplayPlayback, PLAYBACK_SPEEDS};
+use crate::{GameState, FPS};
 use bevy::app::AppExit;
 use bevy::prelude::{
     default, App, AssetServer, Bundle, Camera, Commands, Component, CursorIcon, Entity,
-    EventWriter, In, Plugin, Query, Res, ResMut, RunCriteriaDescriptorCoercion, Windows,
+    EventWriter, In, Local, Plugin, Query, Res, ResMut, Resource, RunCriteriaDescriptorCoercion,
+    Windows,
 };
-use bevy_egui::egui::{Align2, Color32, FontData, FontDefinitions, FontFamily, FontId, Frame, RichText, Stroke, TextStyle};
+use bevy_egui::egui::{Align, Align2, Color32, FontData, FontDefinitions, FontFamily, FontId, Frame, RichText, Stroke, TextStyle};
 use bevy_egui::{egui, EguiContext, EguiPlugin};
 use iyes_loopless::prelude::{AppLooplessStateExt, IntoConditionalSystem, NextState};
 
@@ -16,7 +32,16 @@ impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugin(EguiPlugin)
             .add_enter_system(GameState::Menu,setup_ui)
-            .add_system(main_menu_ui.run_in_state(GameState::Menu));
+            .init_resource::<RoomCodeInput>()
+            .add_system(main_menu_ui.run_in_state(GameState::Menu))
+            .add_system(lobby_ui.run_in_state(GameState::WaitingForPlayers))
+            .add_system(round_hud_ui.run_in_state(GameState::BetweenRound))
+            .add_system(round_hud_ui.run_in_state(GameState::InRound))
+            .add_system(post_match_ui.run_in_state(GameState::PostMatch))
+            .add_system(network_stats_ui)
+            .add_system(connection_banner_ui)
+            .add_system(replay_menu_ui.run_in_state(GameState::Menu))
+            .add_system(replay_ui.run_not_in_state(GameState::Menu));
     }
 }
 
@@ -72,6 +97,20 @@ fn quit_game(mut exit: EventWriter<AppExit>) {
     exit.send(AppExit);
 }
 
+/// The private room code typed in the main menu
+#[derive(Resource, Default)]
+pub struct RoomCodeInput {
+    pub code: String,
+    /// Set when the typed code couldn't be read
+    pub invalid: bool,
+}
+
+/// Connects to the given room on the matchmaking server
+fn join_room(commands: &mut Commands, settings: RoomNetworkSettings) {
+    commands.insert_resource(settings);
+    commands.insert_resource(NextState(GameState::WaitingForPlayers));
+}
+
 pub fn main_menu_ui(
     mut egui_context: ResMut<EguiContext>,
     mut commands: Commands,
@@ -80,6 +119,10 @@ pub fn main_menu_ui(
     cam_query: Query<(Entity, &Camera)>,
     mut exit: EventWriter<AppExit>,
     windows: Res<Windows>,
+    mut match_settings: ResMut<MatchSettings>,
+    mut room_code: ResMut<RoomCodeInput>,
+    mut network_config: ResMut<NetworkConfig>,
+    mut lobby_choices: ResMut<LocalLobbyChoices>,
 ) {
     let wnd = windows.get_primary().unwrap();
 
@@ -102,19 +145,688 @@ pub fn main_menu_ui(
         .collapsible(false)
         .title_bar(false)
         .show(egui_context.ctx_mut(), |ui| {
+            // the format decides which room we are matched into, so everyone in a room agrees on it
+            egui::ComboBox::from_label("PLAYERS")
+                .selected_text(match_settings.format.key())
+                .show_ui(ui, |ui| {
+                    for format in MatchFormat::ALL {
+                        ui.selectable_value(&mut match_settings.format, format, format.key());
+                    }
+                });
+
+            // the GGRS session settings, starting from the config file and the command line
+            egui::CollapsingHeader::new("NETWORK").show(ui, |ui| {
+                ui.add(
+                    egui::Slider::new(
+                        &mut network_config.input_delay,
+                        0..=MAX_ADAPTIVE_INPUT_DELAY,
+                    )
+                    .text("INPUT DELAY"),
+                );
+                ui.checkbox(&mut network_config.adaptive_input_delay, "ADAPTIVE INPUT DELAY");
+                ui.add(
+                    egui::Slider::new(&mut network_config.max_prediction, 1..=16)
+                        .text("MAX PREDICTION"),
+                );
+            });
+
+            // joining a private room with a code from its host, the code decides the format. Watching
+            // joins the room as a spectator
+            ui.horizontal(|ui| {
+                ui.label("ROOM CODE");
+                if ui.text_edit_singleline(&mut room_code.code).changed() {
+                    room_code.invalid = false;
+                }
+                let join = ui.button("JOIN").clicked();
+                let watch = ui.button("WATCH").clicked();
+                if join || watch {
+                    match parse_room_code(&room_code.code) {
+                        Some((format, code)) => {
+                            match_settings.format = format;
+                            lobby_choices.spectator = watch;
+                            join_room(
+                                &mut commands,
+                                RoomNetworkSettings::private_room(&network_config, format, code),
+                            );
+                        }
+                        None => room_code.invalid = true,
+                    }
+                }
+            });
+            if room_code.invalid {
+                ui.label(RichText::new("INVALID ROOM CODE").color(Color32::RED));
+            }
+
             // options below the main panel with system stuff
-            ui.columns(2, |ui| {
+            ui.columns(3, |ui| {
                 let menu_button =
                     ui[0].add_sized([80., 26.], egui::Button::new(RichText::new("QUIT")));
                 if menu_button.clicked() {
                     quit_game(exit);
                 };
                 let menu_button =
-                    ui[1].add_sized([80., 26.], egui::Button::new(RichText::new("PLAY")));
+                    ui[1].add_sized([80., 26.], egui::Button::new(RichText::new("HOST")));
+                if menu_button.clicked() {
+                    let code = generate_room_code(match_settings.format);
+                    lobby_choices.spectator = false;
+                    join_room(
+                        &mut commands,
+                        RoomNetworkSettings::private_room(
+                            &network_config,
+                            match_settings.format,
+                            code,
+                        ),
+                    );
+                };
+                let menu_button =
+                    ui[2].add_sized([80., 26.], egui::Button::new(RichText::new("PLAY")));
+                if menu_button.clicked() {
+                    lobby_choices.spectator = false;
+                    join_room(
+                        &mut commands,
+                        RoomNetworkSettings::matchmake_room(&network_config, match_settings.format),
+                    );
+                };
+            });
+        });
+}
+
+/// Lists the players in the room and lets the local player vote for a map and a game mode and pick a
+/// team, a loadout and a color before readying up. The match starts once the room is full and everyone is ready. Spectators only wait for
+/// the players
+pub fn lobby_ui(
+    mut egui_context: ResMut<EguiContext>,
+    mut commands: Commands,
+    lobby: Option<Res<LobbyState>>,
+    mut choices: ResMut<LocalLobbyChoices>,
+    match_settings: Res<MatchSettings>,
+    settings: Res<RoomNetworkSettings>,
+    map_registry: Res<MapRegistry>,
+    mut map_vote: ResMut<MapVote>,
+    mut mode_vote: ResMut<ModeVote>,
+) {
+    let my_frame = Frame {
+        fill: Color32::from_rgba_unmultiplied(0, 0, 0, 255),
+        stroke: Stroke::new(0., Color32::WHITE),
+        ..default()
+    };
+
+    let format = match_settings.format;
+    let (slots, spectators, status) = lobby
+        .map(|lobby| {
+            (
+                lobby.slots.clone(),
+                lobby.spectators.len(),
+                lobby.status.clone(),
+            )
+        })
+        .unwrap_or_default();
+    let spectating = choices.spectator;
+    let failed = matches!(status, MatchmakingStatus::Failed(_));
+
+    egui::Window::new("lobby_window")
+        .frame(my_frame)
+        .anchor(Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .resizable(false)
+        .collapsible(false)
+        .title_bar(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.vertical_centered(|ui| {
+                ui.label(
+                    RichText::new(format!(
+                        "LOBBY  {} / {}",
+                        slots.len(),
+                        settings.player_count
+                    ))
+                    .size(32.0),
+                );
+                // the host shares this code with the players they want to play with
+                if let MatchmakeType::PrivateRoom(code) = &settings.network_type {
+                    ui.label(format!("ROOM CODE  {}", code));
+                }
+                if spectating {
+                    ui.label("YOU ARE WATCHING THIS MATCH");
+                }
+                if spectators > 0 {
+                    ui.label(format!("{} WATCHING", spectators));
+                }
+                let status_text = match &status {
+                    MatchmakingStatus::Connecting => {
+                        format!("SEARCHING FOR PLAYERS ON {}", settings.signaling_url)
+                    }
+                    MatchmakingStatus::WaitingForPlayers { missing } => {
+                        format!("WAITING FOR {} MORE PLAYERS", missing)
+                    }
+                    MatchmakingStatus::Negotiating => "CONNECTING TO THE PLAYERS".to_string(),
+                    MatchmakingStatus::WaitingForReady => "WAITING FOR EVERYONE TO BE READY".to_string(),
+                    MatchmakingStatus::TooManyPlayers => format!(
+                        "TOO MANY PLAYERS, THE ROOM IS FOR {}",
+                        settings.player_count
+                    ),
+                    MatchmakingStatus::Failed(reason) => reason.clone(),
+                };
+                let status_color = if failed {
+                    Color32::RED
+                } else {
+                    Color32::WHITE
+                };
+                ui.label(RichText::new(status_text).color(status_color));
+            });
+
+            egui::Grid::new("lobby_players").show(ui, |ui| {
+                for (handle, slot) in slots.iter().enumerate() {
+                    let name = if slot.local {
+                        format!("PLAYER {} (YOU)", handle + 1)
+                    } else {
+                        format!("PLAYER {}", handle + 1)
+                    };
+                    ui.label(name);
+                    match slot.player {
+                        Some(player) => {
+                            if let MatchFormat::Teams { .. } = format {
+                                ui.label(match player.team {
+                                    Some(team) => format.team_name(team),
+                                    None => "ANY TEAM".to_string(),
+                                });
+                            }
+                            ui.label(player.loadout.name());
+                            ui.label(player.color.name());
+                            ui.label(map_name(&map_registry, player.level));
+                            ui.label(player.mode.name());
+                            ui.label(if player.ready { "READY" } else { "NOT READY" });
+                        }
+                        None => {
+                            ui.label("CONNECTING");
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+
+            // the choices are locked once ready, so every peer builds the match from the same ones
+            ui.add_enabled_ui(!choices.ready && !spectating, |ui| {
+                // the map this player votes for, the most voted map is played first
+                egui::ComboBox::from_label("MAP")
+                    .selected_text(map_name(&map_registry, map_vote.level))
+                    .show_ui(ui, |ui| {
+                        for map in map_registry.maps.iter() {
+                            ui.selectable_value(
+                                &mut map_vote.level,
+                                map.level_index,
+                                map.identifier.as_str(),
+                            );
+                        }
+                    });
+                // the game mode this player votes for, decided the same way as the map
+                egui::ComboBox::from_label("MODE")
+                    .selected_text(mode_vote.mode.name())
+                    .show_ui(ui, |ui| {
+                        for mode in GameMode::ALL {
+                            ui.selectable_value(&mut mode_vote.mode, mode, mode.name());
+                        }
+                    });
+                egui::ComboBox::from_label("FRIENDLY FIRE")
+                    .selected_text(mode_vote.friendly_fire.name())
+                    .show_ui(ui, |ui| {
+                        for friendly_fire in FriendlyFire::ALL {
+                            ui.selectable_value(
+                                &mut mode_vote.friendly_fire,
+                                friendly_fire,
+                                friendly_fire.name(),
+                            );
+                        }
+                    });
+                if let MatchFormat::Teams { .. } = format {
+                    let selected_team = match choices.team {
+                        Some(team) => format.team_name(team),
+                        None => "ANY".to_string(),
+                    };
+                    egui::ComboBox::from_label("TEAM")
+                        .selected_text(selected_team)
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut choices.team, None, "ANY");
+                            for team in 0..format.team_count() {
+                                ui.selectable_value(
+                                    &mut choices.team,
+                                    Some(team),
+                                    format.team_name(team),
+                                );
+                            }
+                        });
+                }
+                egui::ComboBox::from_label("LOADOUT")
+                    .selected_text(choices.loadout.name())
+                    .show_ui(ui, |ui| {
+                        for loadout in Loadout::ALL {
+                            ui.selectable_value(&mut choices.loadout, loadout, loadout.name());
+                        }
+                    });
+                egui::ComboBox::from_label("COLOR")
+                    .selected_text(choices.color.name())
+                    .show_ui(ui, |ui| {
+                        for color in MageColor::ALL {
+                            ui.selectable_value(&mut choices.color, color, color.name());
+                        }
+                    });
+            });
+
+            ui.columns(2, |ui| {
+                // going back to the menu drops the socket, which leaves the room
+                let leave_button =
+                    ui[0].add_sized([80., 26.], egui::Button::new(RichText::new("CANCEL")));
+                if leave_button.clicked() {
+                    commands.insert_resource(NextState(GameState::Menu));
+                };
+                let ready_text = if choices.ready { "WAITING" } else { "READY" };
+                let ready_button = ui[1].add_enabled(
+                    !failed && !spectating,
+                    egui::Button::new(RichText::new(ready_text)).min_size(egui::vec2(80., 26.)),
+                );
+                if ready_button.clicked() {
+                    choices.ready = true;
+                };
+            });
+        });
+}
+
+/// The name of a level in the map vote
+fn map_name(map_registry: &MapRegistry, level: usize) -> String {
+    map_registry
+        .maps
+        .iter()
+        .find(|map| map.level_index == level)
+        .map(|map| map.identifier.clone())
+        .unwrap_or_default()
+}
+
+/// Shows the score of the match at the top of the screen, and the countdown or the result of the
+/// round in the middle of it
+pub fn round_hud_ui(
+    mut egui_context: ResMut<EguiContext>,
+    match_state: Res<MatchState>,
+    match_settings: Res<MatchSettings>,
+    spectator_count: Option<Res<SpectatorCount>>,
+    spectating: Option<Res<Spectating>>,
+) {
+    let my_frame = Frame {
+        fill: Color32::from_rgba_unmultiplied(0, 0, 0, 160),
+        stroke: Stroke::new(0., Color32::WHITE),
+        ..default()
+    };
+
+    let team_count = match_settings.format.team_count();
+    let score = (0..team_count)
+        .map(|team| match_state.team_score(team).to_string())
+        .collect::<Vec<_>>()
+        .join(" - ");
+
+    egui::Window::new("round_score_window")
+        .frame(my_frame)
+        .anchor(Align2::CENTER_TOP, egui::Vec2 { x: 0.0, y: 10.0 })
+        .resizable(false)
+        .collapsible(false)
+        .title_bar(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.vertical_centered(|ui| {
+                ui.label(format!(
+                    "ROUND {}  (BEST OF {})",
+                    match_state.round + 1,
+                    match_settings.best_of
+                ));
+                ui.label(RichText::new(score).size(28.0));
+                ui.label(match_settings.mode.name());
+
+                // the points each team has towards winning this round
+                if let Some(limit) = match_settings.mode.rules().point_limit(&match_settings) {
+                    let points = (0..team_count)
+                        .map(|team| format!("{}", match_state.round_points(team) as u32))
+                        .collect::<Vec<_>>()
+                        .join(" - ");
+                    ui.label(format!("{}  (TO {})", points, limit as u32));
+                }
+
+                if let Some(count) = spectator_count.filter(|count| count.count > 0) {
+                    ui.label(format!("{} WATCHING", count.count));
+                }
+                if spectating.is_some() {
+                    ui.label("SPECTATING  (TAB TO SWITCH CAMERA)");
+                }
+            });
+        });
+
+    let message = match match_state.phase {
+        RoundPhase::Countdown { remaining } => Some(format!("{}", remaining.ceil().max(1.0) as u32)),
+        RoundPhase::RoundOver { .. } => Some(match match_state.last_round_winner {
+            Some(team) => format!("{} WINS THE ROUND", match_settings.format.team_name(team)),
+            None => "DRAW".to_string(),
+        }),
+        RoundPhase::Fighting | RoundPhase::MatchOver => None,
+    };
+
+    if let Some(message) = message {
+        egui::Window::new("round_message_window")
+            .frame(my_frame)
+            .anchor(Align2::CENTER_CENTER, egui::Vec2::ZERO)
+            .resizable(false)
+            .collapsible(false)
+            .title_bar(false)
+            .show(egui_context.ctx_mut(), |ui| {
+                ui.label(RichText::new(message).size(48.0));
+            });
+    }
+}
+
+/// Shows the winner of the match. The match restarts once every player asked for a rematch
+pub fn post_match_ui(
+    mut egui_context: ResMut<EguiContext>,
+    mut commands: Commands,
+    match_state: Res<MatchState>,
+    match_settings: Res<MatchSettings>,
+    mut rematch_vote: ResMut<LocalRematchVote>,
+    spectating: Option<Res<Spectating>>,
+) {
+    let my_frame = Frame {
+        fill: Color32::from_rgba_unmultiplied(0, 0, 0, 255),
+        stroke: Stroke::new(0., Color32::WHITE),
+        ..default()
+    };
+
+    egui::Window::new("post_match_window")
+        .frame(my_frame)
+        .anchor(Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .resizable(false)
+        .collapsible(false)
+        .title_bar(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.with_layout(egui::Layout::top_down(Align::Center), |ui| {
+                let winner = match match_state.match_winner(&match_settings) {
+                    Some(team) if match_state.forfeit => format!(
+                        "{} WINS BY FORFEIT",
+                        match_settings.format.team_name(team)
+                    ),
+                    Some(team) => format!("{} WINS", match_settings.format.team_name(team)),
+                    None => "MATCH OVER".to_string(),
+                };
+                ui.label(RichText::new(winner).size(48.0));
+
+                for handle in 0..match_settings.format.player_count() {
+                    if match_state.is_disconnected(handle) {
+                        ui.label(format!("PLAYER {} DISCONNECTED", handle + 1));
+                    }
+                }
+
+                let votes = match_state.rematch_votes.count_ones();
+                if votes > 0 {
+                    ui.label(format!("{} WANT A REMATCH", votes));
+                }
+            });
+
+            ui.columns(2, |ui| {
+                let menu_button =
+                    ui[0].add_sized([80., 26.], egui::Button::new(RichText::new("MENU")));
                 if menu_button.clicked() {
-                    commands.insert_resource(RoomNetworkSettings::testing_ip());
-                    commands.insert_resource(NextState(GameState::WaitingForPlayers));
+                    commands.insert_resource(NextState(GameState::Menu));
+                };
+                let rematch_text = if rematch_vote.requested {
+                    "WAITING"
+                } else {
+                    "REMATCH"
+                };
+                // a forfeited match can't be replayed without the players that left, and spectators
+                // have no say in it
+                let rematch_button = ui[1].add_enabled(
+                    !match_state.forfeit && spectating.is_none(),
+                    egui::Button::new(RichText::new(rematch_text)).min_size(egui::vec2(80., 26.)),
+                );
+                if rematch_button.clicked() {
+                    rematch_vote.requested = true;
+                };
+            });
+        });
+}
+
+/// Shows the connection quality to every peer in the corner of the screen, toggled with
+/// [`NETWORK_STATS_KEY`]
+pub fn network_stats_ui(
+    mut egui_context: ResMut<EguiContext>,
+    overlay: Res<NetworkStatsOverlay>,
+    checksums: Res<FrameChecksums>,
+) {
+    if !overlay.visible {
+        return;
+    }
+
+    let my_frame = Frame {
+        fill: Color32::from_rgba_unmultiplied(0, 0, 0, 160),
+        stroke: Stroke::new(0., Color32::WHITE),
+        ..default()
+    };
+
+    egui::Window::new("network_stats_window")
+        .frame(my_frame)
+        .anchor(Align2::RIGHT_TOP, egui::Vec2 { x: -10.0, y: 10.0 })
+        .resizable(false)
+        .collapsible(false)
+        .title_bar(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.label(format!("NETWORK  ({:?})", NETWORK_STATS_KEY));
+            if overlay.peers.is_empty() {
+                ui.label("NOT CONNECTED");
+                return;
+            }
+
+            egui::Grid::new("network_stats_peers").show(ui, |ui| {
+                ui.label("PLAYER");
+                ui.label("PING");
+                ui.label("KBPS");
+                ui.label("LOCAL BEHIND");
+                ui.label("REMOTE BEHIND");
+                ui.end_row();
+                for (handle, stats) in overlay.peers.iter() {
+                    ui.label(format!("{}", handle + 1));
+                    ui.label(format!("{}MS", stats.ping));
+                    ui.label(format!("{}", stats.kbps_sent));
+                    ui.label(format!("{}", stats.local_frames_behind));
+                    ui.label(format!("{}", stats.remote_frames_behind));
+                    ui.end_row();
+                }
+            });
+            ui.label(format!(
+                "ROLLBACK FRAMES/S  {}",
+                overlay.rollback_frames_per_second
+            ));
+            ui.label(format!("PREDICTED FRAMES  {}", overlay.predicted_frames));
+            if let Some(frame) = checksums.desyncs.last() {
+                ui.label(
+                    RichText::new(format!("DESYNC AT FRAME {}", frame)).color(Color32::RED),
+                );
+            }
+        });
+}
+
+/// Warns about peers we haven't heard from. The game stalls while they are gone, and they forfeit if
+/// they don't come back before the disconnect timeout
+pub fn connection_banner_ui(
+    mut egui_context: ResMut<EguiContext>,
+    connection_status: Res<ConnectionStatus>,
+) {
+    if connection_status.interrupted.is_empty() {
+        return;
+    }
+
+    let my_frame = Frame {
+        fill: Color32::from_rgba_unmultiplied(120, 0, 0, 200),
+        stroke: Stroke::new(0., Color32::WHITE),
+        ..default()
+    };
+
+    egui::Window::new("connection_banner_window")
+        .frame(my_frame)
+        .anchor(Align2::CENTER_TOP, egui::Vec2 { x: 0.0, y: 120.0 })
+        .resizable(false)
+        .collapsible(false)
+        .title_bar(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.vertical_centered(|ui| {
+                for peer in connection_status.interrupted.iter() {
+                    ui.label(format!(
+                        "RECONNECTING TO PLAYER {}  ({:.1}S)",
+                        peer.handle + 1,
+                        peer.disconnects_in
+                    ));
+                }
+            });
+        });
+}
+
+/// How many of the latest replays are listed in the menu
+const LISTED_REPLAYS: usize = 10;
+
+/// Lists the recorded matches in the menu, picking one plays it back
+pub fn replay_menu_ui(
+    mut egui_context: ResMut<EguiContext>,
+    mut commands: Commands,
+    mut browser: ResMut<ReplayBrowser>,
+    map_registry: Res<MapRegistry>,
+    map_assets: Res<MapAssets>,
+) {
+    let my_frame = Frame {
+        fill: Color32::from_rgba_unmultiplied(0, 0, 0, 255),
+        stroke: Stroke::new(0., Color32::WHITE),
+        ..default()
+    };
+
+    egui::Window::new("replays_window")
+        .frame(my_frame)
+        .anchor(Align2::RIGHT_BOTTOM, egui::Vec2 { x: -10.0, y: -10.0 })
+        .resizable(false)
+        .collapsible(false)
+        .title_bar(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            let mut picked = None;
+            egui::CollapsingHeader::new("REPLAYS").show(ui, |ui| {
+                if browser.files.is_empty() {
+                    ui.label("NO MATCHES RECORDED YET");
+                }
+                for path in browser.files.iter().take(LISTED_REPLAYS) {
+                    let name = path
+                        .file_stem()
+                        .map(|name| name.to_string_lossy().to_uppercase())
+                        .unwrap_or_default();
+                    if ui.button(name).clicked() {
+                        picked = Some(path.clone());
+                    }
+                }
+            });
+            if let Some(error) = &browser.error {
+                ui.label(RichText::new(error).color(Color32::RED));
+            }
+
+            if let Some(path) = picked {
+                match Replay::load(&path) {
+                    Ok(replay) => {
+                        browser.error = None;
+                        start_replay(
+                            &mut commands,
+                            ReplayPlayback::new(replay),
+                            &map_registry,
+                            &map_assets,
+                        );
+                    }
+                    Err(error) => browser.error = Some(error),
+                }
+            }
+        });
+}
+
+/// Formats seconds as minutes and seconds
+fn clock(seconds: f32) -> String {
+    let seconds = seconds as u32;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// The playback controls of a replay: pause, speed, seeking and which player the camera follows
+pub fn replay_ui(
+    mut egui_context: ResMut<EguiContext>,
+    mut commands: Commands,
+    playback: Option<ResMut<ReplayPlayback>>,
+    frame: Res<RollbackFrame>,
+    match_settings: Res<MatchSettings>,
+    mut spectator_camera: ResMut<SpectatorCamera>,
+    // where the seek bar is being dragged to, the replay only seeks once it is let go
+    mut dragged_to: Local<Option<f32>>,
+) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+
+    let my_frame = Frame {
+        fill: Color32::from_rgba_unmultiplied(0, 0, 0, 200),
+        stroke: Stroke::new(0., Color32::WHITE),
+        ..default()
+    };
+
+    egui::Window::new("replay_window")
+        .frame(my_frame)
+        .anchor(Align2::CENTER_BOTTOM, egui::Vec2 { x: 0.0, y: -10.0 })
+        .resizable(false)
+        .collapsible(false)
+        .title_bar(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            let total = playback.replay.frame_count() as f32 / FPS as f32;
+            let mut position = dragged_to.unwrap_or(frame.frame as f32 / FPS as f32);
+            ui.horizontal(|ui| {
+                let seek_bar =
+                    ui.add(egui::Slider::new(&mut position, 0.0..=total).show_value(false));
+                ui.label(format!("{} / {}", clock(position), clock(total)));
+                if seek_bar.dragged() {
+                    *dragged_to = Some(position);
+                } else if seek_bar.drag_released() || seek_bar.changed() {
+                    *dragged_to = None;
+                    // seeking backwards plays the replay again from the start
+                    if playback.seek((position * FPS as f32) as u32, frame.frame) {
+                        commands.insert_resource(NextState(GameState::Menu));
+                    }
+                }
+            });
+
+            ui.horizontal(|ui| {
+                let pause_text = if playback.paused { "PLAY" } else { "PAUSE" };
+                if ui.button(pause_text).clicked() {
+                    playback.paused = !playback.paused;
+                }
+                egui::ComboBox::from_label("SPEED")
+                    .selected_text(format!("{}X", playback.speed))
+                    .show_ui(ui, |ui| {
+                        for speed in PLAYBACK_SPEEDS {
+                            ui.selectable_value(&mut playback.speed, speed, format!("{}X", speed));
+                        }
+                    });
+                let camera_text = match spectator_camera.mode {
+                    SpectatorCameraMode::Free => "FREE".to_string(),
+                    SpectatorCameraMode::Follow(handle) => format!("PLAYER {}", handle + 1),
                 };
+                egui::ComboBox::from_label("CAMERA")
+                    .selected_text(camera_text)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut spectator_camera.mode,
+                            SpectatorCameraMode::Free,
+                            "FREE",
+                        );
+                        for handle in 0..match_settings.format.player_count() {
+                            ui.selectable_value(
+                                &mut spectator_camera.mode,
+                                SpectatorCameraMode::Follow(handle),
+                                format!("PLAYER {}", handle + 1),
+                            );
+                        }
+                    });
+                if ui.button("MENU").clicked() {
+                    commands.remove_resource::<ReplayPlayback>();
+                    commands.insert_resource(NextState(GameState::Menu));
+                }
             });
         });
 }
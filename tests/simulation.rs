@@ -0,0 +1,276 @@
+use mg23::headless::{
+    HeadlessMatch, Loadout, MageColor, MatchSettings, MovementState, PlayerControls,
+    PlayerSnapshot, RosterChoice, RoundPhase, Vec2, CAST_SPELL, DASH, EXPLOSION_SPELL, REMATCH,
+};
+
+fn battlemage() -> RosterChoice {
+    RosterChoice {
+        team: None,
+        loadout: Loadout::Battlemage,
+        color: MageColor::Red,
+    }
+}
+
+/// Player 0 on the left side of the arena against player 1 on the right side
+fn one_v_one(settings: MatchSettings) -> HeadlessMatch {
+    HeadlessMatch::new(settings, &[battlemage(), battlemage()])
+}
+
+fn walk(direction: Vec2) -> PlayerControls {
+    PlayerControls {
+        move_direction: direction,
+        ..Default::default()
+    }
+}
+
+fn explosion_at(target: Vec2) -> PlayerControls {
+    PlayerControls {
+        action_vars: CAST_SPELL,
+        cast_spell_type: EXPLOSION_SPELL,
+        mouse_position: target,
+        ..Default::default()
+    }
+}
+
+/// The middle of the player's collider
+fn center(player: &PlayerSnapshot) -> Vec2 {
+    player.position + Vec2::new(2.5, 8.0)
+}
+
+#[test]
+fn players_only_move_once_the_countdown_is_over() {
+    let mut game = one_v_one(MatchSettings::default());
+    let spawn = game.player(0).position;
+
+    game.run(30, |_, _| walk(Vec2::X));
+    assert_eq!(game.player(0).position, spawn);
+
+    game.skip_countdown();
+    game.run(60, |_, handle| {
+        if handle == 0 {
+            walk(Vec2::X)
+        } else {
+            PlayerControls::default()
+        }
+    });
+
+    let player = game.player(0);
+    assert_eq!(player.movement_state, MovementState::Walking);
+    assert!((player.position.x - spawn.x - 160.0).abs() < 1.0);
+    assert!((player.position.y - spawn.y).abs() < f32::EPSILON);
+}
+
+#[test]
+fn dashing_covers_more_ground_than_walking() {
+    let mut game = one_v_one(MatchSettings::default());
+    game.skip_countdown();
+    let dasher_spawn = game.player(0).position;
+    let walker_spawn = game.player(1).position;
+
+    game.step(&[
+        PlayerControls {
+            action_vars: DASH,
+            ..walk(Vec2::X)
+        },
+        walk(Vec2::NEG_X),
+    ]);
+    let dasher = game.player(0);
+    assert!(matches!(
+        dasher.movement_state,
+        MovementState::Dashing { .. }
+    ));
+    assert!(!dasher.can_dash);
+
+    game.run(29, |_, handle| {
+        if handle == 0 {
+            walk(Vec2::X)
+        } else {
+            walk(Vec2::NEG_X)
+        }
+    });
+
+    let dasher = game.player(0);
+    let walker = game.player(1);
+    assert_eq!(dasher.movement_state, MovementState::Walking);
+    let dashed = dasher.position.x - dasher_spawn.x;
+    let walked = walker_spawn.x - walker.position.x;
+    assert!(
+        dashed > walked + 30.0,
+        "dashed {} walked {}",
+        dashed,
+        walked
+    );
+}
+
+#[test]
+fn walls_stop_players() {
+    let mut game = one_v_one(MatchSettings::default());
+    game.add_wall(Vec2::new(-200.0, -100.0), Vec2::new(16.0, 200.0));
+    game.skip_countdown();
+    let spawn = game.player(0).position;
+
+    game.run(120, |_, _| walk(Vec2::X));
+
+    let player = game.player(0);
+    assert!(player.position.x > spawn.x + 40.0);
+    assert!(player.position.x + 5.0 <= -200.0 + 0.01);
+
+    // the arena is walled in too
+    game.run(180, |_, _| walk(Vec2::NEG_X));
+    assert!(game.player(0).position.x >= -320.0 - 0.01);
+}
+
+#[test]
+fn explosions_damage_enemies_once_they_go_off() {
+    let mut game = one_v_one(MatchSettings::default());
+
+    // nobody can cast during the countdown
+    let target = center(&game.player(1));
+    game.step(&[explosion_at(target), PlayerControls::default()]);
+    assert_eq!(game.spell_count(), 0);
+
+    game.skip_countdown();
+    game.step(&[explosion_at(target), PlayerControls::default()]);
+    assert_eq!(game.spell_count(), 1);
+
+    // the cast delay is a second
+    game.run(30, |_, _| PlayerControls::default());
+    assert_eq!(game.player(1).health, game.player(1).max_health);
+
+    game.run(40, |_, _| PlayerControls::default());
+    let enemy = game.player(1);
+    assert_eq!(enemy.health, enemy.max_health - 30);
+    let caster = game.player(0);
+    assert_eq!(caster.health, caster.max_health);
+    assert_eq!(game.spell_count(), 0);
+}
+
+#[test]
+fn killing_the_last_enemy_wins_the_round_and_the_match() {
+    let mut game = one_v_one(MatchSettings {
+        best_of: 3,
+        ..Default::default()
+    });
+
+    for round in 0..2 {
+        game.skip_countdown();
+        assert_eq!(game.match_state().round, round);
+
+        // four explosions take all 120 health of a battlemage
+        let target = center(&game.player(1));
+        game.run(4, |_, handle| {
+            if handle == 0 {
+                explosion_at(target)
+            } else {
+                PlayerControls::default()
+            }
+        });
+        assert!(game.idle_until(120, |state| matches!(
+            state.phase,
+            RoundPhase::RoundOver { .. }
+        )));
+        assert_eq!(game.player(1).health, 0);
+        assert_eq!(game.match_state().last_round_winner, Some(0));
+        assert_eq!(game.match_state().team_scores[0], round + 1);
+
+        if round == 0 {
+            // the next round starts with everyone back to full health
+            assert!(game.idle_until(300, |state| matches!(
+                state.phase,
+                RoundPhase::Countdown { .. }
+            )));
+            let enemy = game.player(1);
+            assert_eq!(enemy.health, enemy.max_health);
+        }
+    }
+
+    assert!(game.idle_until(300, |state| state.phase == RoundPhase::MatchOver));
+    assert!(!game.match_state().forfeit);
+
+    // the match starts over once everyone asked for a rematch
+    game.step(&[
+        PlayerControls {
+            action_vars: REMATCH,
+            ..Default::default()
+        },
+        PlayerControls::default(),
+    ]);
+    assert_eq!(game.match_state().phase, RoundPhase::MatchOver);
+    game.run(1, |_, _| PlayerControls {
+        action_vars: REMATCH,
+        ..Default::default()
+    });
+    assert!(matches!(
+        game.match_state().phase,
+        RoundPhase::Countdown { .. }
+    ));
+    assert_eq!(game.match_state().round, 0);
+    assert!(game
+        .match_state()
+        .team_scores
+        .iter()
+        .all(|score| *score == 0));
+}
+
+#[test]
+fn the_remaining_team_wins_when_the_other_disconnects() {
+    let mut game = one_v_one(MatchSettings::default());
+    game.skip_countdown();
+
+    game.disconnect(1);
+    game.step(&[PlayerControls::default(), PlayerControls::default()]);
+
+    let state = game.match_state();
+    assert_eq!(state.phase, RoundPhase::MatchOver);
+    assert!(state.forfeit);
+    assert_eq!(state.forfeit_winner, Some(0));
+    assert_eq!(game.player(1).health, 0);
+}
+
+/// Players walking in circles, dashing and casting now and then
+fn wander(frame: u32, handle: usize) -> PlayerControls {
+    let angle = frame as f32 * 0.05 + handle as f32;
+    let mut input = walk(Vec2::new(angle.cos(), angle.sin()));
+    if frame % 45 == 0 {
+        input.action_vars |= DASH;
+    }
+    if frame % 100 == 0 {
+        input.action_vars |= CAST_SPELL;
+        input.mouse_position = Vec2::new(angle.sin() * 200.0, 0.0);
+    }
+    input
+}
+
+#[test]
+fn the_same_inputs_always_play_out_the_same() {
+    let mut first = one_v_one(MatchSettings::default());
+    let mut second = one_v_one(MatchSettings::default());
+    first.run(600, wander);
+    second.run(600, wander);
+
+    assert!(first.latest_checksum().is_some());
+    assert_eq!(first.latest_checksum(), second.latest_checksum());
+    for handle in 0..2 {
+        assert_eq!(first.player(handle), second.player(handle));
+    }
+    assert_eq!(first.match_state(), second.match_state());
+}
+
+#[test]
+fn replays_play_out_the_recorded_match() {
+    let mut recorded = one_v_one(MatchSettings::default());
+    recorded.run(600, wander);
+    let replay = recorded.replay();
+    assert_eq!(replay.frame_count(), 600);
+
+    let mut played = HeadlessMatch::from_replay(&replay);
+    played.play_replay(&replay);
+
+    assert_eq!(played.frame(), recorded.frame());
+    assert!(recorded.latest_checksum().is_some());
+    assert_eq!(played.latest_checksum(), recorded.latest_checksum());
+    for handle in 0..2 {
+        assert_eq!(played.player(handle), recorded.player(handle));
+    }
+    assert_eq!(played.match_state(), recorded.match_state());
+}